@@ -0,0 +1,176 @@
+use crate::{
+    character_config::CharacterConfig,
+    components::{CameraBoom, CameraBoomHandle, Spectator},
+    floating_origin::FloatingOrigin,
+    input::{ActionBinding, GameBindings},
+    marching_cubes::CUTOFF,
+    states::RunState,
+    Terrain,
+};
+use amethyst::{
+    core::{math::Vector3, Parent, Time, Transform},
+    ecs::prelude::*,
+    input::InputEvent,
+    shrev::EventChannel,
+};
+use std::sync::Arc;
+
+/// Distance between the spheres tested along the boom, as a fraction of their radius.
+const CAST_STEP: f32 = 0.5;
+
+/// Moves cameras with a `CameraBoom` along their boom: behind and beside the
+/// `CameraBoomHandle` they are parented to in third person, onto it in first person.
+///
+/// The boom is sphere-cast against the terrain density, and the camera is pulled
+/// in front of the first hit so terrain never ends up between it and the character.
+pub struct CameraBoomSystem {
+    input_event_reader: Option<ReaderId<InputEvent<GameBindings>>>,
+}
+
+impl CameraBoomSystem {
+    pub fn new() -> Self {
+        CameraBoomSystem {
+            input_event_reader: None,
+        }
+    }
+}
+
+impl<'s> System<'s> for CameraBoomSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, Time>,
+        Read<'s, RunState>,
+        ReadExpect<'s, CharacterConfig>,
+        Read<'s, EventChannel<InputEvent<GameBindings>>>,
+        Read<'s, Arc<Terrain>>,
+        Read<'s, FloatingOrigin>,
+        ReadStorage<'s, Parent>,
+        ReadStorage<'s, CameraBoomHandle>,
+        ReadStorage<'s, Spectator>,
+        WriteStorage<'s, CameraBoom>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            run_state,
+            config,
+            input_event_channel,
+            terrain,
+            origin,
+            parents,
+            handles,
+            spectators,
+            mut booms,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        let mut toggle = false;
+        let mut zoom = 0.0;
+        for e in input_event_channel.read(self.input_event_reader.as_mut().unwrap()) {
+            match e {
+                InputEvent::ActionPressed(ActionBinding::ToggleCamera) => toggle = !toggle,
+                InputEvent::ActionPressed(ActionBinding::ZoomIn) => zoom -= config.zoom_step,
+                InputEvent::ActionPressed(ActionBinding::ZoomOut) => zoom += config.zoom_step,
+                _ => {}
+            }
+        }
+        if *run_state != RunState::Playing {
+            toggle = false;
+            zoom = 0.0;
+        }
+        // The scroll wheel sets the spectator's speed instead.
+        if (&spectators).join().next().is_some() {
+            zoom = 0.0;
+        }
+
+        let chunk_size = terrain.chunk_size();
+        let solid = |posn: Vector3<f32>| {
+            terrain.density_at(&origin.absolute(&posn, chunk_size)) < CUTOFF
+        };
+        let radius = config.camera_radius;
+        let sphere_hits = |center: Vector3<f32>| {
+            solid(center)
+                || [
+                    Vector3::x(),
+                    -Vector3::x(),
+                    Vector3::y(),
+                    -Vector3::y(),
+                    Vector3::z(),
+                    -Vector3::z(),
+                ]
+                .iter()
+                .any(|direction| solid(center + direction * radius))
+        };
+        // Smoothing that converges at the same rate whatever the frame rate.
+        let blend = 1.0 - (-config.zoom_smoothing * time.delta_seconds()).exp();
+
+        let step = (radius * CAST_STEP).max(0.01);
+
+        for (camera, boom, parent) in (&entities, &mut booms, &parents).join() {
+            if toggle {
+                boom.third_person = !boom.third_person;
+            }
+            boom.length = (boom.length + zoom)
+                .max(config.min_boom_length)
+                .min(config.max_boom_length);
+            let (target_distance, target_shoulder) = if boom.third_person {
+                (boom.length, config.shoulder_offset)
+            } else {
+                (0.0, 0.0)
+            };
+            boom.distance += (target_distance - boom.distance) * blend;
+            boom.shoulder += (target_shoulder - boom.shoulder) * blend;
+
+            // The handle sits on a root entity whose rotation is locked, so its
+            // world position is its parent's translation plus its own.
+            if !handles.contains(parent.entity) {
+                continue;
+            }
+            let (pivot, rotation) = match transforms.get(parent.entity) {
+                Some(handle) => {
+                    let base = parents
+                        .get(parent.entity)
+                        .and_then(|p| transforms.get(p.entity))
+                        .map(|t| *t.translation())
+                        .unwrap_or_else(Vector3::zeros);
+                    (base + handle.translation(), *handle.rotation())
+                }
+                None => continue,
+            };
+
+            let right = rotation * Vector3::x();
+            let back = rotation * Vector3::z();
+            let shoulder = if sphere_hits(pivot + right * boom.shoulder) {
+                0.0
+            } else {
+                boom.shoulder
+            };
+            let start = pivot + right * shoulder;
+            let mut distance = 0.0;
+            while distance < boom.distance {
+                let next = (distance + step).min(boom.distance);
+                if sphere_hits(start + back * next) {
+                    break;
+                }
+                distance = next;
+            }
+            // Pull in at once, but zoom back out smoothly once the way is clear.
+            boom.distance = distance;
+
+            if let Some(transform) = transforms.get_mut(camera) {
+                transform.set_translation_xyz(shoulder, 0.0, distance);
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        let mut ie = world.fetch_mut::<EventChannel<InputEvent<GameBindings>>>();
+        self.input_event_reader = Some(ie.register_reader());
+    }
+}
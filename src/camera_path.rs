@@ -0,0 +1,178 @@
+use crate::config;
+use amethyst::{
+    core::math::{UnitQuaternion, Vector3},
+    Error,
+};
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use splines::{Interpolation, Key, Spline};
+use std::{fs, path::Path};
+
+/// How a `CameraPath` moves between its keys.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathInterpolation {
+    Linear,
+    /// A smooth curve through every key.
+    CatmullRom,
+    /// Curves shaped by each key's `control` point.
+    Bezier,
+}
+
+/// A pose the camera passes through. Positions are absolute world units,
+/// counted from chunk zero; angles are in degrees.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraKey {
+    /// Seconds from the start of the path.
+    pub time: f32,
+    pub position: (f64, f64, f64),
+    /// Turn to the left, from looking down -z.
+    pub yaw: f32,
+    /// Positive upwards.
+    pub pitch: f32,
+    /// Where a `Bezier` path heads towards when leaving this key; it arrives
+    /// from the mirror image. Defaults to the position itself.
+    pub control: Option<(f64, f64, f64)>,
+}
+
+/// A flythrough, authored in RON or recorded with `RecordKeyframe`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraPath {
+    pub interpolation: PathInterpolation,
+    /// Seconds between keys recorded with `RecordKeyframe`.
+    pub key_interval: f32,
+    pub keys: Vec<CameraKey>,
+}
+
+impl Default for CameraPath {
+    fn default() -> Self {
+        CameraPath {
+            interpolation: PathInterpolation::CatmullRom,
+            key_interval: 2.0,
+            keys: vec![],
+        }
+    }
+}
+
+impl CameraPath {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        return config::load_ron(path);
+    }
+
+    /// Overwrites `path`, so any comments in it are lost.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, to_string_pretty(self, PrettyConfig::default())?)?;
+        return Ok(());
+    }
+
+    /// Appends a key `key_interval` seconds after the last one.
+    pub fn push(&mut self, position: Vector3<f64>, rotation: UnitQuaternion<f32>) {
+        let forward = rotation * -Vector3::z();
+        let pitch = forward.y.max(-1.0).min(1.0).asin().to_degrees();
+        let mut yaw = (-forward.x).atan2(-forward.z).to_degrees();
+        let time = match self.keys.last() {
+            Some(last) => {
+                // Turn the short way round from the previous key.
+                while yaw - last.yaw > 180.0 {
+                    yaw -= 360.0;
+                }
+                while yaw - last.yaw < -180.0 {
+                    yaw += 360.0;
+                }
+                last.time + self.key_interval
+            }
+            None => 0.0,
+        };
+        self.keys.push(CameraKey {
+            time,
+            position: (position.x, position.y, position.z),
+            yaw,
+            pitch,
+            control: None,
+        });
+    }
+
+    /// The curves to play the path back with, or `None` if it has no keys.
+    pub fn curves(&self) -> Option<CameraCurves> {
+        let last = self.keys.last()?;
+        return Some(CameraCurves {
+            x: self.curve(|key| (key.position.0, key.control.map(|c| c.0))),
+            y: self.curve(|key| (key.position.1, key.control.map(|c| c.1))),
+            z: self.curve(|key| (key.position.2, key.control.map(|c| c.2))),
+            yaw: self.curve(|key| (key.yaw as f64, None)),
+            pitch: self.curve(|key| (key.pitch as f64, None)),
+            duration: last.time,
+            end: last.clone(),
+        });
+    }
+
+    /// A spline through one component of every key: its value and, for `Bezier`
+    /// paths, its control point.
+    fn curve(&self, component: impl Fn(&CameraKey) -> (f64, Option<f64>)) -> Spline<f64, f64> {
+        let mut keys: Vec<Key<f64, f64>> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let (value, control) = component(key);
+                let interpolation = match self.interpolation {
+                    PathInterpolation::Linear => Interpolation::Linear,
+                    PathInterpolation::CatmullRom => Interpolation::CatmullRom,
+                    PathInterpolation::Bezier => Interpolation::Bezier(control.unwrap_or(value)),
+                };
+                Key::new(key.time as f64, value, interpolation)
+            })
+            .collect();
+        // Catmull-Rom needs a key on either side of the part being sampled, so
+        // repeat the first and last keys just outside of the path.
+        if let (Some(first), Some(last)) = (keys.first().cloned(), keys.last().cloned()) {
+            keys.insert(0, Key::new(first.t - 1.0, first.value, first.interpolation));
+            keys.push(Key::new(last.t + 1.0, last.value, last.interpolation));
+        }
+        return Spline::from_vec(keys);
+    }
+}
+
+/// A `CameraPath` ready to be sampled.
+pub struct CameraCurves {
+    x: Spline<f64, f64>,
+    y: Spline<f64, f64>,
+    z: Spline<f64, f64>,
+    yaw: Spline<f64, f64>,
+    pitch: Spline<f64, f64>,
+    duration: f32,
+    end: CameraKey,
+}
+
+impl CameraCurves {
+    pub fn duration(&self) -> f32 {
+        return self.duration;
+    }
+
+    /// Absolute position and rotation `time` seconds into the path.
+    /// Past the end, the pose of the last key.
+    pub fn sample(&self, time: f32) -> (Vector3<f64>, UnitQuaternion<f32>) {
+        let t = time.max(0.0) as f64;
+        let sampled = if time < self.duration {
+            match (
+                self.x.sample(t),
+                self.y.sample(t),
+                self.z.sample(t),
+                self.yaw.sample(t),
+                self.pitch.sample(t),
+            ) {
+                (Some(x), Some(y), Some(z), Some(yaw), Some(pitch)) => {
+                    Some((Vector3::new(x, y, z), yaw as f32, pitch as f32))
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+        let (position, yaw, pitch) = sampled.unwrap_or_else(|| {
+            let (x, y, z) = self.end.position;
+            (Vector3::new(x, y, z), self.end.yaw, self.end.pitch)
+        });
+        let rotation = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), yaw.to_radians())
+            * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), pitch.to_radians());
+        return (position, rotation);
+    }
+}
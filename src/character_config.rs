@@ -0,0 +1,99 @@
+use crate::config::{self, FileWatcher};
+use amethyst::{ecs::prelude::*, renderer::Camera, window::ScreenDimensions};
+use log::{info, warn};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Nearest distance the camera renders.
+const NEAR_PLANE: f32 = 0.1;
+
+/// Movement and camera tuning, read from `config/character.ron` and reloaded when it changes.
+/// Speeds are in world units per second, angles in degrees.
+#[derive(Deserialize, Clone)]
+pub struct CharacterConfig {
+    pub mouse_sensitivity: f32,
+    /// Turn rate at full stick deflection, in the same units as mouse motion.
+    pub stick_look_speed: f32,
+    /// Moving the mouse or stick up looks down.
+    pub invert_y: bool,
+    pub max_pitch_angle: f32,
+    /// Vertical field of view.
+    pub fov: f32,
+    pub walk_speed: f32,
+    pub sprint_multiplier: f32,
+    pub fly_multiplier: f32,
+    /// Change in velocity per second while on the ground, in the air, and flying.
+    pub ground_acceleration: f32,
+    pub air_acceleration: f32,
+    pub fly_acceleration: f32,
+    /// Seconds after leaving the ground during which a jump is still allowed.
+    pub coyote_time: f32,
+    pub jump_impulse: f32,
+    /// Whether the camera starts in third person.
+    pub third_person: bool,
+    /// Boom length the third person camera starts at, and the range it can be zoomed in.
+    pub boom_length: f32,
+    pub min_boom_length: f32,
+    pub max_boom_length: f32,
+    /// Change in boom length per scroll wheel notch.
+    pub zoom_step: f32,
+    /// How quickly the camera follows zoom and mode changes; higher is snappier.
+    pub zoom_smoothing: f32,
+    /// Sideways offset of the third person camera, positive to the right.
+    pub shoulder_offset: f32,
+    /// Radius of the sphere kept clear of terrain around the camera.
+    pub camera_radius: f32,
+    /// Speed the spectator camera flies at, and the range scrolling can change it in.
+    pub spectator_speed: f32,
+    pub min_spectator_speed: f32,
+    pub max_spectator_speed: f32,
+    /// Factor the spectator speed changes by per scroll wheel notch.
+    pub spectator_speed_step: f32,
+}
+
+impl CharacterConfig {
+    /// A camera projection with the configured field of view.
+    pub fn camera(&self, width: f32, height: f32) -> Camera {
+        return Camera::perspective(width / height, self.fov.to_radians(), NEAR_PLANE);
+    }
+}
+
+/// Reloads the `CharacterConfig` when its file changes, and applies the new field of view.
+pub struct CharacterConfigReloadSystem {
+    watcher: FileWatcher,
+}
+
+impl CharacterConfigReloadSystem {
+    pub fn new(path: PathBuf) -> Self {
+        CharacterConfigReloadSystem {
+            watcher: FileWatcher::new(path),
+        }
+    }
+}
+
+impl<'s> System<'s> for CharacterConfigReloadSystem {
+    type SystemData = (
+        WriteExpect<'s, CharacterConfig>,
+        ReadExpect<'s, ScreenDimensions>,
+        WriteStorage<'s, Camera>,
+    );
+
+    fn run(&mut self, (mut character_config, dimensions, mut cameras): Self::SystemData) {
+        if !self.watcher.changed() {
+            return;
+        }
+        match config::load_ron::<CharacterConfig>(self.watcher.path()) {
+            Ok(loaded) => {
+                info!("reloaded {:?}", self.watcher.path());
+                *character_config = loaded;
+            }
+            Err(e) => {
+                warn!("not reloading character config: {}", e);
+                return;
+            }
+        }
+        for camera in (&mut cameras).join() {
+            *camera = character_config.camera(dimensions.width(), dimensions.height());
+        }
+    }
+}
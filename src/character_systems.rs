@@ -1,143 +1,181 @@
 use amethyst::{
     core::{
-        math::{Matrix4, UnitQuaternion, Vector3},
+        math::{UnitQuaternion, Vector3},
         Transform,
     },
     ecs::prelude::*,
-    input::{InputEvent, StringBindings},
-    renderer::Camera,
-    shrev::EventChannel,
+    input::InputEvent,
 };
-use amethyst_physics::prelude::*;
+use amethyst_physics::{prelude::*, servers::ContactEvent};
+use std::sync::Arc;
 
-use crate::components::*;
+use crate::{
+    character_config::CharacterConfig,
+    components::*,
+    floating_origin::FloatingOrigin,
+    input::{ActionBinding, AxisBinding},
+    input_tape::StepInput,
+    marching_cubes::CUTOFF,
+    states::RunState,
+    Terrain,
+};
 
-const MOUSE_SENSITIVITY: f32 = 0.2;
-const MAX_PITCH_ANGLE: f32 = 80.0;
-const FORCE_MULTIPLIER: f32 = 200.0;
-const JUMP_IMPULSE: f32 = 30.0;
-const MAX_THRUST_VEL: f32 = 5.0;
+/// How far past the capsule to look for a ledge to step onto.
+const STEP_PROBE_DISTANCE: f32 = 0.1;
+/// Extra height added when stepping up, so the capsule clears the ledge.
+const STEP_CLEARANCE: f32 = 0.05;
+/// Spacing of the terrain samples taken when probing for ground or ledges.
+const PROBE_INTERVAL: f32 = 0.1;
 
+/// Turns the camera boom handle, or the spectator, with the `StepInput`.
+/// Runs every physics step, so recorded input turns it the same way on replay.
 #[derive(Debug)]
-pub struct CameraMotionSystem {
-    input_event_reader: Option<ReaderId<InputEvent<StringBindings>>>,
-}
-
-impl CameraMotionSystem {
-    pub fn new() -> Self {
-        CameraMotionSystem {
-            input_event_reader: None,
-        }
-    }
-}
+pub struct CameraMotionSystem;
 
 impl<'s> System<'s> for CameraMotionSystem {
     #[allow(clippy::type_complexity)]
     type SystemData = (
+        Entities<'s>,
         ReadExpect<'s, PhysicsTime>,
-        ReadExpect<'s, EventChannel<InputEvent<StringBindings>>>,
+        Read<'s, RunState>,
+        ReadExpect<'s, CharacterConfig>,
+        Read<'s, StepInput>,
         ReadStorage<'s, CameraBoomHandle>,
+        ReadStorage<'s, Spectator>,
+        ReadStorage<'s, OnCameraPath>,
         WriteStorage<'s, Transform>,
     );
 
     fn run(
         &mut self,
-        (physics_time, input_event_channel, camera_boom_handles, mut transforms): Self::SystemData,
+        (
+            entities,
+            physics_time,
+            run_state,
+            config,
+            input,
+            camera_boom_handles,
+            spectators,
+            on_path,
+            mut transforms,
+        ): Self::SystemData,
     ) {
         // Capture the input
         let motion = {
             let mut m_motion_x = 0.0;
             let mut m_motion_y = 0.0;
 
-            for e in input_event_channel.read(self.input_event_reader.as_mut().unwrap()) {
+            // A step can hold several mouse events, so all of their motion counts.
+            for e in input.events.iter() {
                 if let InputEvent::MouseMoved { delta_x, delta_y } = e {
-                    m_motion_x = *delta_y;
-                    m_motion_y = *delta_x * -1.0;
-                    break;
+                    m_motion_x += *delta_y;
+                    m_motion_y -= *delta_x;
                 }
             }
+            // Stick deflection turns the camera like mouse motion in the same direction.
+            let stick_pitch = input.axis_value(&AxisBinding::LookPitch);
+            let stick_yaw = input.axis_value(&AxisBinding::LookYaw);
+            let pitch_sign = if config.invert_y { -1.0 } else { 1.0 };
             (
-                m_motion_x * MOUSE_SENSITIVITY,
-                m_motion_y * MOUSE_SENSITIVITY,
+                (m_motion_x * config.mouse_sensitivity - stick_pitch * config.stick_look_speed)
+                    * pitch_sign,
+                m_motion_y * config.mouse_sensitivity - stick_yaw * config.stick_look_speed,
             )
         };
 
-        for (transform, _) in (&mut transforms, &camera_boom_handles).join() {
-            // Clamp the pitch rotation by avoiding further rotations.
-            let pitch_clamper = {
-                let angles = transform.isometry().rotation.euler_angles();
+        // The cursor is free outside of play, and moving it shouldn't look around.
+        if *run_state != RunState::Playing {
+            return;
+        }
 
-                let mut pitch_deg = angles.0.to_degrees();
+        // Turn the spectator while there is one, the character's camera otherwise.
+        // A spectator following a camera path isn't turned at all.
+        let target = match (&entities, &spectators).join().next() {
+            Some((e, _)) if on_path.contains(e) => None,
+            Some((e, _)) => Some(e),
+            None => (&entities, &camera_boom_handles).join().map(|(e, _)| e).next(),
+        };
+        let transform = match target.and_then(|e| transforms.get_mut(e)) {
+            Some(transform) => transform,
+            None => return,
+        };
 
-                if angles.2.abs() > std::f32::consts::FRAC_PI_2 {
-                    // Invert the pitch
-                    if pitch_deg < 0.0 {
-                        pitch_deg = pitch_deg + 180.0;
-                    } else {
-                        pitch_deg = pitch_deg - 180.0;
-                    }
-                }
-                if pitch_deg > MAX_PITCH_ANGLE || pitch_deg < -MAX_PITCH_ANGLE {
-                    if pitch_deg.signum() != motion.0.signum() {
-                        1.0
-                    } else {
-                        0.0
-                    }
+        // Clamp the pitch rotation by avoiding further rotations.
+        let pitch_clamper = {
+            let angles = transform.isometry().rotation.euler_angles();
+
+            let mut pitch_deg = angles.0.to_degrees();
+
+            if angles.2.abs() > std::f32::consts::FRAC_PI_2 {
+                // Invert the pitch
+                if pitch_deg < 0.0 {
+                    pitch_deg = pitch_deg + 180.0;
                 } else {
+                    pitch_deg = pitch_deg - 180.0;
+                }
+            }
+            if pitch_deg > config.max_pitch_angle || pitch_deg < -config.max_pitch_angle {
+                if pitch_deg.signum() != motion.0.signum() {
                     1.0
+                } else {
+                    0.0
                 }
-            };
-
-            let delta_rotation_pitch = UnitQuaternion::from_axis_angle(
-                &Vector3::x_axis(),
-                motion.0 * pitch_clamper * physics_time.delta_seconds(),
-            );
-            let delta_rotation_yaw = UnitQuaternion::from_axis_angle(
-                &Vector3::y_axis(),
-                motion.1 * physics_time.delta_seconds(),
-            );
-
-            transform.isometry_mut().rotation =
-                delta_rotation_yaw * transform.isometry().rotation * delta_rotation_pitch;
+            } else {
+                1.0
+            }
+        };
 
-            break; // Actually is supported only 1 player
-        }
-    }
+        let delta_rotation_pitch = UnitQuaternion::from_axis_angle(
+            &Vector3::x_axis(),
+            motion.0 * pitch_clamper * physics_time.delta_seconds(),
+        );
+        let delta_rotation_yaw = UnitQuaternion::from_axis_angle(
+            &Vector3::y_axis(),
+            motion.1 * physics_time.delta_seconds(),
+        );
 
-    fn setup(&mut self, world: &mut World) {
-        Self::SystemData::setup(world);
-        let mut ie = world.fetch_mut::<EventChannel<InputEvent<StringBindings>>>();
-        self.input_event_reader = Some(ie.register_reader());
+        transform.isometry_mut().rotation =
+            delta_rotation_yaw * transform.isometry().rotation * delta_rotation_pitch;
     }
 }
 
+/// Drives the `CharacterBody`, either walking under gravity or flying (`ToggleFly`).
+///
+/// Velocities are steered towards a target at a bounded acceleration per second
+/// of physics time, so movement feels the same at any physics frame rate.
 pub struct CharacterMotionControllerSystem {
-    input_event_reader: Option<ReaderId<InputEvent<StringBindings>>>,
-    horizontal_input: Vector3<f32>,
-    vertical_input: f32,
-    jump_time: f32,
-    sprint: bool,
+    flying: bool,
+    /// Set when `Jump` is pressed, consumed by the next physics step.
+    jump_requested: bool,
+    /// Seconds since the character last stood on the ground.
+    since_grounded: f32,
+    contacts: Vec<ContactEvent<f32>>,
 }
 
 impl CharacterMotionControllerSystem {
     pub fn new() -> Self {
         Self {
-            input_event_reader: None,
-            horizontal_input: Vector3::zeros(),
-            vertical_input: 0.0,
-            jump_time: 0.0,
-            sprint: false,
+            flying: false,
+            jump_requested: false,
+            since_grounded: std::f32::INFINITY,
+            contacts: vec![],
         }
     }
 }
 
 impl<'s> System<'s> for CharacterMotionControllerSystem {
+    #[allow(clippy::type_complexity)]
     type SystemData = (
         ReadExpect<'s, PhysicsWorld<f32>>,
         ReadExpect<'s, PhysicsTime>,
-        ReadExpect<'s, EventChannel<InputEvent<StringBindings>>>,
+        ReadExpect<'s, CharacterConfig>,
+        Read<'s, StepInput>,
+        Read<'s, Arc<Terrain>>,
+        Read<'s, FloatingOrigin>,
         ReadStorage<'s, CharacterBody>,
-        ReadStorage<'s, Camera>,
+        ReadStorage<'s, CharacterController>,
+        ReadStorage<'s, CameraBoomHandle>,
+        ReadStorage<'s, Spectator>,
         ReadStorage<'s, PhysicsHandle<PhysicsRigidBodyTag>>,
         ReadStorage<'s, Transform>,
     );
@@ -147,108 +185,196 @@ impl<'s> System<'s> for CharacterMotionControllerSystem {
         (
             physics_world,
             physics_time,
-            input_event_channel,
+            config,
+            input,
+            terrain,
+            origin,
             character_bodies,
-            cameras,
+            controllers,
+            camera_boom_handles,
+            spectators,
             rigid_body_tags,
             transforms,
         ): Self::SystemData,
     ) {
-        for e in input_event_channel.read(self.input_event_reader.as_mut().unwrap()) {
+        // While spectating, input flies the spectator and the character stands still.
+        let spectating = (&spectators).join().next().is_some();
+        for e in input.events.iter() {
+            if spectating {
+                continue;
+            }
             if let InputEvent::ActionPressed(action) = e {
-                match action.as_str() {
-                    "Forward" => {
-                        self.horizontal_input.z -= 1.0;
-                    }
-                    "Backward" => {
-                        self.horizontal_input.z += 1.0;
-                    }
-                    "Right" => {
-                        self.horizontal_input.x -= 1.0;
-                    }
-                    "Left" => {
-                        self.horizontal_input.x += 1.0;
-                    }
-                    "Jump" => {
-                        self.vertical_input += 1.0;
-                    }
-                    "Sprint" => {
-                        self.sprint = true;
-                    }
-                    _ => {}
-                }
-            } else if let InputEvent::ActionReleased(action) = e {
-                match action.as_str() {
-                    "Forward" => {
-                        self.horizontal_input.z += 1.0;
-                    }
-                    "Backward" => {
-                        self.horizontal_input.z -= 1.0;
-                    }
-                    "Right" => {
-                        self.horizontal_input.x += 1.0;
-                    }
-                    "Left" => {
-                        self.horizontal_input.x -= 1.0;
-                    }
-                    "Jump" => {
-                        self.vertical_input -= 1.0;
-                    }
-                    "Sprint" => {
-                        self.sprint = false;
-                    }
+                match action {
+                    ActionBinding::Jump => self.jump_requested = true,
+                    ActionBinding::ToggleFly => self.flying = !self.flying,
                     _ => {}
                 }
             }
         }
-        let horizontal_input;
-        if self.sprint {
-            horizontal_input = self.horizontal_input.scale(3.0);
+        let is_down = |action: ActionBinding| !spectating && input.action_is_down(&action);
+        let axis = |axis: AxisBinding| {
+            if spectating {
+                return 0.0;
+            }
+            return input.axis_value(&axis);
+        };
+        let horizontal_input = Vector3::new(
+            axis(AxisBinding::MoveRight),
+            0.0,
+            -axis(AxisBinding::MoveForward),
+        );
+        let vertical_input = match (is_down(ActionBinding::Jump), is_down(ActionBinding::Descend)) {
+            (true, false) => 1.0,
+            (false, true) => -1.0,
+            _ => 0.0,
+        };
+        let speed = if is_down(ActionBinding::Sprint) {
+            config.walk_speed * config.sprint_multiplier
         } else {
-            horizontal_input = self.horizontal_input;
-        }
+            config.walk_speed
+        };
 
-        let mut camera_pos = Matrix4::<f32>::identity();
-        for (t, _) in (&transforms, &cameras).join() {
-            camera_pos = t.global_matrix().clone();
+        // Input relative to where the camera looks, flattened onto the ground. The boom
+        // handle's own rotation is where it looks, as the character's rotation is locked;
+        // global matrices would lag behind when a frame runs several physics steps.
+        let look = (&transforms, &camera_boom_handles)
+            .join()
+            .next()
+            .map(|(t, _)| *t.rotation())
+            .unwrap_or_else(UnitQuaternion::identity);
+        let mut direction = look * horizontal_input;
+        direction.y = 0.0;
+        if direction.norm() > 0.001 {
+            direction = direction.normalize();
         }
 
-        for (body_tag, _) in (&rigid_body_tags, &character_bodies).join() {
-            let velocity = physics_world
-                .rigid_body_server()
-                .linear_velocity(body_tag.get());
+        let delta = physics_time.delta_seconds();
+        let jump_requested = self.jump_requested;
+        self.jump_requested = false;
+
+        for (body_tag, controller, _) in
+            (&rigid_body_tags, &controllers, &character_bodies).join()
+        {
+            let server = physics_world.rigid_body_server();
+            let velocity = server.linear_velocity(body_tag.get());
+
+            if self.flying {
+                let mut target = direction * speed * config.fly_multiplier;
+                target.y = vertical_input * speed * config.fly_multiplier;
+                // Setting the velocity every step cancels out gravity.
+                server.set_linear_velocity(
+                    body_tag.get(),
+                    &accelerate(velocity, target, config.fly_acceleration * delta),
+                );
+                self.since_grounded = std::f32::INFINITY;
+                break;
+            }
+
+            let min_ground_normal_y = controller.max_slope_angle.to_radians().cos();
+            let chunk_size = terrain.chunk_size();
+            let solid = |posn: Vector3<f32>| {
+                terrain.density_at(&origin.absolute(&posn, chunk_size)) < CUTOFF
+            };
+            let mut isometry = server.transform(body_tag.get());
+            let feet = isometry.translation.vector - Vector3::new(0.0, controller.feet_offset, 0.0);
+
+            self.contacts.clear();
+            server.contact_events(body_tag.get(), &mut self.contacts);
+            // Contact normals point from the character to what it touches.
+            let mut grounded = self
+                .contacts
+                .iter()
+                .any(|contact| -contact.normal.y >= min_ground_normal_y);
+            let blocked = direction.norm() > 0.0
+                && self.contacts.iter().any(|contact| {
+                    -contact.normal.y < min_ground_normal_y && contact.normal.dot(&direction) > 0.0
+                });
 
-            physics_world.rigid_body_server().apply_force(
+            // Walking down a slope or off a small drop: stick to the ground
+            // instead of briefly flying off it.
+            if !grounded && self.since_grounded == 0.0 && velocity.y <= 0.0 {
+                let drop = probe(controller.snap_distance, |height| {
+                    solid(feet - Vector3::new(0.0, height, 0.0))
+                });
+                if let Some(drop) = drop {
+                    isometry.translation.vector.y -= drop;
+                    server.set_transform(body_tag.get(), &isometry);
+                    grounded = true;
+                }
+            }
+
+            // Walking into a ledge no higher than `step_height`: climb onto it.
+            if self.since_grounded < config.coyote_time && blocked {
+                let ahead = feet + direction * (controller.radius + STEP_PROBE_DISTANCE);
+                if solid(ahead) {
+                    let rise = probe(controller.step_height, |height| {
+                        let step = ahead + Vector3::new(0.0, height, 0.0);
+                        !solid(step) && !solid(step + Vector3::new(0.0, controller.radius, 0.0))
+                    });
+                    if let Some(rise) = rise {
+                        isometry.translation.vector.y += rise + STEP_CLEARANCE;
+                        server.set_transform(body_tag.get(), &isometry);
+                    }
+                }
+            }
+
+            if grounded {
+                self.since_grounded = 0.0;
+            } else {
+                self.since_grounded += delta;
+            }
+
+            let acceleration = if self.since_grounded < config.coyote_time {
+                config.ground_acceleration
+            } else {
+                config.air_acceleration
+            };
+            let mut target = direction * speed;
+            // Standing on a walkable slope, don't let gravity slide the character down it.
+            target.y = if grounded {
+                velocity.y.max(0.0)
+            } else {
+                velocity.y
+            };
+            server.set_linear_velocity(
                 body_tag.get(),
-                &Vector3::new(
-                    0.0,
-                    self.vertical_input * JUMP_IMPULSE * 0.0f32.max(MAX_THRUST_VEL - velocity[1]),
-                    0.0,
-                ),
+                &accelerate(velocity, target, acceleration * delta),
             );
-            self.jump_time = 0.0;
-
-            // Apply motion force
-            let mut force = camera_pos.transform_vector(&horizontal_input);
-            force.y = 0.0; // Don't apply any force on Y axis
-            physics_world
-                .rigid_body_server()
-                .apply_force(body_tag.get(), &(force * FORCE_MULTIPLIER));
-
-            // Compute breaking force
-            let mut bk_force = (velocity / physics_time.delta_seconds()) * -1.0;
-            bk_force.y = 0.0;
-            physics_world
-                .rigid_body_server()
-                .apply_force(body_tag.get(), &bk_force);
+
+            if jump_requested && self.since_grounded < config.coyote_time {
+                if velocity.y < 0.0 {
+                    // Falling off a ledge shouldn't eat into the jump.
+                    let mut landed = server.linear_velocity(body_tag.get());
+                    landed.y = 0.0;
+                    server.set_linear_velocity(body_tag.get(), &landed);
+                }
+                server.apply_impulse(body_tag.get(), &Vector3::new(0.0, config.jump_impulse, 0.0));
+                // One jump per touch of the ground.
+                self.since_grounded = std::f32::INFINITY;
+            }
 
             break; // Actually only 1 player is allowed;
         }
     }
+}
 
-    fn setup(&mut self, world: &mut World) {
-        Self::SystemData::setup(world);
-        let mut ie = world.fetch_mut::<EventChannel<InputEvent<StringBindings>>>();
-        self.input_event_reader = Some(ie.register_reader());
+/// Moves `velocity` towards `target` by at most `max_change`.
+fn accelerate(velocity: Vector3<f32>, target: Vector3<f32>, max_change: f32) -> Vector3<f32> {
+    let change = target - velocity;
+    if change.norm() <= max_change {
+        return target;
+    }
+    return velocity + change.normalize() * max_change;
+}
+
+/// The smallest height, in steps of `PROBE_INTERVAL` up to `max`, at which `hit` is true.
+fn probe(max: f32, hit: impl Fn(f32) -> bool) -> Option<f32> {
+    let mut height = 0.0;
+    while height <= max {
+        if hit(height) {
+            return Some(height);
+        }
+        height += PROBE_INTERVAL;
     }
+    return None;
 }
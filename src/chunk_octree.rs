@@ -0,0 +1,232 @@
+use crate::{
+    chunk_registry::{ChunkCoord, ChunkRegistry},
+    terrain::{Homogeneity, Terrain},
+};
+
+/// Nodes at this level are never subdivided: each covers 2^LEAF_LEVEL chunks per side,
+/// and all of a mixed leaf's chunks are queued.
+pub const LEAF_LEVEL: u32 = 1;
+/// The largest nodes cover 2^ROOT_LEVEL chunks per side.
+pub const ROOT_LEVEL: u32 = 6;
+
+/// An inclusive box of chunks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChunkBox {
+    pub min: ChunkCoord,
+    pub max: ChunkCoord,
+}
+
+impl ChunkBox {
+    /// The chunks within `radius` chunks of `center` on every axis.
+    pub fn around(center: &ChunkCoord, radius: i64) -> Self {
+        ChunkBox {
+            min: center.offset(-radius, -radius, -radius),
+            max: center.offset(radius, radius, radius),
+        }
+    }
+
+    pub fn overlaps(&self, other: &ChunkBox) -> bool {
+        return self.min.x <= other.max.x
+            && other.min.x <= self.max.x
+            && self.min.y <= other.max.y
+            && other.min.y <= self.max.y
+            && self.min.z <= other.max.z
+            && other.min.z <= self.max.z;
+    }
+
+    fn intersection(&self, other: &ChunkBox) -> ChunkBox {
+        ChunkBox {
+            min: ChunkCoord::new(
+                self.min.x.max(other.min.x),
+                self.min.y.max(other.min.y),
+                self.min.z.max(other.min.z),
+            ),
+            max: ChunkCoord::new(
+                self.max.x.min(other.max.x),
+                self.max.y.min(other.max.y),
+                self.max.z.min(other.max.z),
+            ),
+        }
+    }
+}
+
+/// A cube of 2^level chunks per side, aligned to multiples of its size.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct OctreeNode {
+    pub level: u32,
+    pub min: ChunkCoord,
+}
+
+impl OctreeNode {
+    /// The node at `level` containing `coord`.
+    pub fn containing(coord: &ChunkCoord, level: u32) -> Self {
+        let size = 1i64 << level;
+        OctreeNode {
+            level,
+            min: ChunkCoord::new(
+                coord.x.div_euclid(size) * size,
+                coord.y.div_euclid(size) * size,
+                coord.z.div_euclid(size) * size,
+            ),
+        }
+    }
+
+    pub fn size(&self) -> i64 {
+        return 1 << self.level;
+    }
+
+    pub fn bounds(&self) -> ChunkBox {
+        let last = self.size() - 1;
+        ChunkBox {
+            min: self.min,
+            max: self.min.offset(last, last, last),
+        }
+    }
+
+    fn children(&self) -> Vec<OctreeNode> {
+        let half = self.size() / 2;
+        let mut children = Vec::with_capacity(8);
+        for x in 0..2 {
+            for y in 0..2 {
+                for z in 0..2 {
+                    children.push(OctreeNode {
+                        level: self.level - 1,
+                        min: self.min.offset(x * half, y * half, z * half),
+                    });
+                }
+            }
+        }
+        return children;
+    }
+}
+
+/// The chunks in `region` that may contain the surface.
+///
+/// Most of the world is solid rock or open air. Rather than look at every chunk,
+/// space is split into an octree whose nodes are classified as a whole with
+/// `Terrain::classify_rows`, largest first. The registry records every node it
+/// classifies; homogeneous ones stand in for all of their chunks, and only mixed
+/// ones are subdivided, down to `LEAF_LEVEL`.
+pub fn surface_chunks(
+    registry: &mut ChunkRegistry,
+    terrain: &Terrain,
+    region: &ChunkBox,
+) -> Vec<ChunkCoord> {
+    let mut chunks = vec![];
+    let root = OctreeNode::containing(&region.min, ROOT_LEVEL);
+    let size = root.size();
+    let mut x = root.min.x;
+    while x <= region.max.x {
+        let mut y = root.min.y;
+        while y <= region.max.y {
+            let mut z = root.min.z;
+            while z <= region.max.z {
+                let node = OctreeNode {
+                    level: ROOT_LEVEL,
+                    min: ChunkCoord::new(x, y, z),
+                };
+                visit(registry, terrain, node, region, &mut chunks);
+                z += size;
+            }
+            y += size;
+        }
+        x += size;
+    }
+    return chunks;
+}
+
+fn visit(
+    registry: &mut ChunkRegistry,
+    terrain: &Terrain,
+    node: OctreeNode,
+    region: &ChunkBox,
+    chunks: &mut Vec<ChunkCoord>,
+) {
+    let bounds = node.bounds();
+    if !bounds.overlaps(region) {
+        return;
+    }
+    let homogeneity =
+        registry.classify_node(node, || terrain.classify_rows(bounds.min.y, bounds.max.y));
+    if homogeneity != Homogeneity::Mixed {
+        return;
+    }
+    if node.level > LEAF_LEVEL {
+        for child in node.children() {
+            visit(registry, terrain, child, region, chunks);
+        }
+        return;
+    }
+    let inside = bounds.intersection(region);
+    for x in inside.min.x..(inside.max.x + 1) {
+        for y in inside.min.y..(inside.max.y + 1) {
+            for z in inside.min.z..(inside.max.z + 1) {
+                chunks.push(ChunkCoord::new(x, y, z));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk_registry::ChunkState;
+
+    /// The default noise, with chunks 60 units tall, so the band of rows that
+    /// may contain the surface is only a few chunks thick.
+    fn terrain() -> Terrain {
+        return Terrain::new(7, 15, 4.0, vec![0.3, 0.65, 0.05], vec![0.05, 0.1, 10.0]);
+    }
+
+    fn queue_surface(registry: &mut ChunkRegistry, terrain: &Terrain, region: &ChunkBox) {
+        for coord in surface_chunks(registry, terrain, region) {
+            registry.queue(coord);
+        }
+    }
+
+    #[test]
+    fn every_chunk_is_queued_or_covered_by_a_homogeneous_node() {
+        let terrain = terrain();
+        let mut registry = ChunkRegistry::default();
+        let region = ChunkBox::around(&ChunkCoord::new(3, 0, -5), 9);
+        queue_surface(&mut registry, &terrain, &region);
+        for x in region.min.x..(region.max.x + 1) {
+            for y in region.min.y..(region.max.y + 1) {
+                for z in region.min.z..(region.max.z + 1) {
+                    let coord = ChunkCoord::new(x, y, z);
+                    match registry.state(&coord) {
+                        Some(ChunkState::Queued) => {
+                            let leaf = OctreeNode::containing(&coord, LEAF_LEVEL).bounds();
+                            let homogeneity = terrain.classify_rows(leaf.min.y, leaf.max.y);
+                            assert_eq!(homogeneity, Homogeneity::Mixed, "{:?}", coord);
+                        }
+                        Some(ChunkState::Empty) => {
+                            assert_eq!(terrain.classify_chunk(&coord), Homogeneity::Empty);
+                        }
+                        Some(ChunkState::Solid) => {
+                            assert_eq!(terrain.classify_chunk(&coord), Homogeneity::Solid);
+                        }
+                        state => panic!("{:?} is {:?}", coord, state),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn registry_grows_with_the_surface_not_the_volume() {
+        let terrain = terrain();
+        for radius in &[8, 16, 32] {
+            let mut registry = ChunkRegistry::default();
+            let region = ChunkBox::around(&ChunkCoord::new(0, 0, 0), *radius);
+            queue_surface(&mut registry, &terrain, &region);
+            let mixed_rows = (region.min.y..(region.max.y + 1))
+                .filter(|y| terrain.classify_rows(*y, *y) == Homogeneity::Mixed)
+                .count();
+            // Leaves are two chunks tall, so the band can grow by a row on either side.
+            let side = (2 * radius + 1) as usize;
+            assert!(registry.len() <= side * side * (mixed_rows + 2));
+            assert!(registry.homogeneous_nodes() < side * side);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{
+    chunk_octree::{OctreeNode, LEAF_LEVEL, ROOT_LEVEL},
+    terrain::Homogeneity,
+};
+use amethyst::{core::math::Vector3, ecs::Entity};
+use std::collections::HashMap;
+
+/// Integer coordinate of a chunk in chunk space.
+/// Coordinates are absolute, that is independent of the `FloatingOrigin`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ChunkCoord {
+    pub x: i64,
+    pub y: i64,
+    pub z: i64,
+}
+
+impl ChunkCoord {
+    pub fn new(x: i64, y: i64, z: i64) -> Self {
+        ChunkCoord { x, y, z }
+    }
+
+    /// Returns the chunk containing the world position `posn`.
+    /// Uses floor division so negative positions map to negative chunks
+    /// (e.g. `-0.5` lies in chunk `-1`, not chunk `0`).
+    pub fn from_world(posn: &Vector3<f32>, chunk_size: f32) -> Self {
+        ChunkCoord {
+            x: (posn.x / chunk_size).floor() as i64,
+            y: (posn.y / chunk_size).floor() as i64,
+            z: (posn.z / chunk_size).floor() as i64,
+        }
+    }
+
+    pub fn offset(&self, x: i64, y: i64, z: i64) -> Self {
+        ChunkCoord::new(self.x + x, self.y + y, self.z + z)
+    }
+
+    /// The coordinate of this chunk counted from `other`.
+    pub fn relative_to(&self, other: &ChunkCoord) -> Self {
+        ChunkCoord::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+
+    /// Position of the chunk's minimum corner, counted from chunk zero. Use a
+    /// coordinate `relative_to` the `FloatingOrigin` chunk to get a world position.
+    pub fn world_origin(&self, chunk_size: f32) -> Vector3<f32> {
+        return Vector3::new(self.x as f32, self.y as f32, self.z as f32).scale(chunk_size);
+    }
+
+    /// Position of the chunk's center, counted from chunk zero.
+    pub fn world_center(&self, chunk_size: f32) -> Vector3<f32> {
+        return self.world_origin(chunk_size) + Vector3::repeat(chunk_size / 2.0);
+    }
+}
+
+/// Axis-aligned bounds of a chunk in world space, as of when they were computed
+/// (a `FloatingOrigin` shift moves the world under them).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChunkBounds {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl ChunkBounds {
+    pub fn new(coord: &ChunkCoord, chunk_size: f32) -> Self {
+        let min = coord.world_origin(chunk_size);
+        ChunkBounds {
+            min,
+            max: min + Vector3::repeat(chunk_size),
+        }
+    }
+}
+
+/// Lifecycle of a chunk tracked by the `ChunkRegistry`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChunkState {
+    /// Waiting to be generated.
+    Queued,
+    /// Generated, with a mesh entity.
+    Ready,
+    /// Generated, but the chunk produced no triangles, so there is no entity.
+    /// Chunks proven to be entirely above the surface, on their own or as part
+    /// of an octree node, skip straight to this state.
+    Empty,
+    /// Entirely below the surface, proven without sampling. There is no entity.
+    Solid,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ChunkEntry {
+    pub state: ChunkState,
+    pub entity: Option<Entity>,
+}
+
+/// Resource tracking every chunk the `ChunkSystem` knows about.
+///
+/// Only chunks that may contain the surface get an entry of their own. The
+/// octree nodes classified by `chunk_octree::surface_chunks` are kept too, and
+/// the ones proven entirely empty or solid report that state for all of their
+/// chunks, so memory follows the surface rather than the loaded volume.
+#[derive(Default)]
+pub struct ChunkRegistry {
+    chunks: HashMap<ChunkCoord, ChunkEntry>,
+    nodes: HashMap<OctreeNode, Homogeneity>,
+}
+
+impl ChunkRegistry {
+    pub fn state(&self, coord: &ChunkCoord) -> Option<ChunkState> {
+        if let Some(entry) = self.chunks.get(coord) {
+            return Some(entry.state);
+        }
+        for level in LEAF_LEVEL..(ROOT_LEVEL + 1) {
+            match self.nodes.get(&OctreeNode::containing(coord, level)) {
+                Some(Homogeneity::Empty) => return Some(ChunkState::Empty),
+                Some(Homogeneity::Solid) => return Some(ChunkState::Solid),
+                Some(Homogeneity::Mixed) | None => {}
+            }
+        }
+        return None;
+    }
+
+    /// Returns true if the chunk has been generated, whether or not it has a mesh.
+    pub fn is_loaded(&self, coord: &ChunkCoord) -> bool {
+        match self.state(coord) {
+            Some(ChunkState::Ready)
+            | Some(ChunkState::Empty)
+            | Some(ChunkState::Solid) => true,
+            _ => false,
+        }
+    }
+
+    /// The node's classification, calling `classify` the first time it is asked for.
+    pub fn classify_node(
+        &mut self,
+        node: OctreeNode,
+        classify: impl FnOnce() -> Homogeneity,
+    ) -> Homogeneity {
+        return *self.nodes.entry(node).or_insert_with(classify);
+    }
+
+    /// Forgets the classified nodes `keep` returns false for.
+    pub fn retain_nodes(&mut self, mut keep: impl FnMut(&OctreeNode) -> bool) {
+        self.nodes.retain(|node, _| keep(node));
+    }
+
+    /// Octree nodes known to be entirely empty or solid.
+    pub fn homogeneous_nodes(&self) -> usize {
+        return self
+            .nodes
+            .values()
+            .filter(|homogeneity| **homogeneity != Homogeneity::Mixed)
+            .count();
+    }
+
+    /// Queues the chunk for generation. Returns false if it is already known.
+    pub fn queue(&mut self, coord: ChunkCoord) -> bool {
+        if self.chunks.contains_key(&coord) {
+            return false;
+        }
+        self.chunks.insert(
+            coord,
+            ChunkEntry {
+                state: ChunkState::Queued,
+                entity: None,
+            },
+        );
+        return true;
+    }
+
+    pub fn set_state(&mut self, coord: &ChunkCoord, state: ChunkState) {
+        if let Some(entry) = self.chunks.get_mut(coord) {
+            entry.state = state;
+        }
+    }
+
+    pub fn set_entity(&mut self, coord: &ChunkCoord, entity: Option<Entity>) {
+        if let Some(entry) = self.chunks.get_mut(coord) {
+            entry.entity = entity;
+        }
+    }
+
+    pub fn remove(&mut self, coord: &ChunkCoord) -> Option<ChunkEntry> {
+        return self.chunks.remove(coord);
+    }
+
+    /// The chunks with an entry of their own.
+    pub fn iter(&self) -> impl Iterator<Item = (&ChunkCoord, &ChunkEntry)> {
+        return self.chunks.iter();
+    }
+
+    pub fn coords_in_state(&self, state: ChunkState) -> Vec<ChunkCoord> {
+        return self
+            .chunks
+            .iter()
+            .filter(|(_, entry)| entry.state == state)
+            .map(|(coord, _)| *coord)
+            .collect();
+    }
+
+    /// Number of chunks with an entry of their own.
+    pub fn len(&self) -> usize {
+        return self.chunks.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: f32 = 16.0;
+
+    fn chunk_at(x: f32, y: f32, z: f32) -> ChunkCoord {
+        return ChunkCoord::from_world(&Vector3::new(x, y, z), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn from_world_floors_negative_positions() {
+        assert_eq!(chunk_at(-0.5, -0.5, -0.5), ChunkCoord::new(-1, -1, -1));
+        assert_eq!(chunk_at(-CHUNK_SIZE, 0.0, 0.0), ChunkCoord::new(-1, 0, 0));
+        assert_eq!(chunk_at(0.0, -CHUNK_SIZE - 0.5, 0.0), ChunkCoord::new(0, -2, 0));
+        assert_eq!(chunk_at(0.0, 0.0, -2.0 * CHUNK_SIZE), ChunkCoord::new(0, 0, -2));
+    }
+
+    #[test]
+    fn from_world_keeps_positive_positions_in_their_chunk() {
+        assert_eq!(chunk_at(0.0, 0.0, 0.0), ChunkCoord::new(0, 0, 0));
+        assert_eq!(chunk_at(0.5, CHUNK_SIZE - 0.5, CHUNK_SIZE), ChunkCoord::new(0, 0, 1));
+        assert_eq!(chunk_at(3.0 * CHUNK_SIZE + 1.0, 0.0, 0.0), ChunkCoord::new(3, 0, 0));
+    }
+
+    #[test]
+    fn chunks_either_side_of_zero_are_distinct() {
+        let mut registry = ChunkRegistry::default();
+        assert!(registry.queue(chunk_at(-0.5, 0.0, 0.0)));
+        assert!(registry.queue(chunk_at(0.5, 0.0, 0.0)));
+        assert_eq!(registry.len(), 2);
+        assert!(registry.remove(&chunk_at(-0.5, 0.0, 0.0)).is_some());
+        assert_eq!(registry.state(&ChunkCoord::new(-1, 0, 0)), None);
+        assert_eq!(registry.state(&ChunkCoord::new(0, 0, 0)), Some(ChunkState::Queued));
+    }
+}
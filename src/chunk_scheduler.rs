@@ -0,0 +1,149 @@
+use crate::{
+    chunk_octree::{self, ChunkBox},
+    chunk_registry::{ChunkCoord, ChunkRegistry, ChunkState},
+    components::ChunkLoader,
+    floating_origin::FloatingOrigin,
+    terrain::{Homogeneity, Terrain},
+};
+use amethyst::core::math::Vector3;
+use std::cmp::Ordering;
+
+/// Chunks stay loaded until they are this many chunks outside of every loader's radius,
+/// so walking back and forth over a boundary doesn't regenerate them.
+const UNLOAD_MARGIN: i64 = 2;
+
+/// The chunks a `ChunkLoader` keeps loaded.
+#[derive(Debug, Copy, Clone)]
+pub struct LoaderRegion {
+    /// Absolute coordinate of the chunk the loader is in.
+    pub center: ChunkCoord,
+    /// Position of the loader within the `center` chunk.
+    pub offset: Vector3<f32>,
+    pub radius: i64,
+    pub priority: i32,
+}
+
+impl LoaderRegion {
+    pub fn new(
+        position: Vector3<f32>,
+        loader: &ChunkLoader,
+        chunk_size: f32,
+        origin: &FloatingOrigin,
+    ) -> Self {
+        let center = origin.chunk_at(&position, chunk_size);
+        LoaderRegion {
+            center,
+            offset: position - origin.chunk_origin(&center, chunk_size),
+            radius: loader.radius as i64,
+            priority: loader.priority,
+        }
+    }
+
+    /// Number of chunks between `coord` and the center, on the axis where it is furthest.
+    fn chunk_distance(&self, coord: &ChunkCoord) -> i64 {
+        return (coord.x - self.center.x)
+            .abs()
+            .max((coord.y - self.center.y).abs())
+            .max((coord.z - self.center.z).abs());
+    }
+
+    pub fn contains(&self, coord: &ChunkCoord) -> bool {
+        return self.chunk_distance(coord) <= self.radius;
+    }
+
+    pub fn bounds(&self) -> ChunkBox {
+        return ChunkBox::around(&self.center, self.radius);
+    }
+}
+
+/// Queues the chunks inside any of the regions that may contain the surface
+/// and aren't known yet. The rest of each region is covered by octree nodes the
+/// registry knows to be entirely empty or solid. Nodes well outside of every
+/// region are forgotten, like the chunks `chunks_to_unload` returns.
+pub fn queue_regions(registry: &mut ChunkRegistry, terrain: &Terrain, regions: &[LoaderRegion]) {
+    for region in regions {
+        for coord in chunk_octree::surface_chunks(registry, terrain, &region.bounds()) {
+            registry.queue(coord);
+        }
+    }
+    let kept: Vec<ChunkBox> = regions
+        .iter()
+        .map(|region| ChunkBox::around(&region.center, region.radius + UNLOAD_MARGIN))
+        .collect();
+    registry.retain_nodes(|node| kept.iter().any(|region| node.bounds().overlaps(region)));
+}
+
+/// Chunks that are well outside of every region.
+pub fn chunks_to_unload(registry: &ChunkRegistry, regions: &[LoaderRegion]) -> Vec<ChunkCoord> {
+    return registry
+        .iter()
+        .map(|(coord, _)| *coord)
+        .filter(|coord| {
+            regions
+                .iter()
+                .all(|region| region.chunk_distance(coord) > region.radius + UNLOAD_MARGIN)
+        })
+        .collect();
+}
+
+/// The queued chunks to handle in one run, in generation order, with how they
+/// were classified: every chunk proven entirely empty or solid, as those cost
+/// next to nothing, and the first `budget` that need to be sampled and meshed.
+pub fn next_chunks(
+    registry: &ChunkRegistry,
+    regions: &[LoaderRegion],
+    terrain: &Terrain,
+    budget: usize,
+) -> Vec<(ChunkCoord, Homogeneity)> {
+    let mut mixed = 0;
+    let mut chunks = vec![];
+    for coord in generation_order(registry, regions, terrain.chunk_size()) {
+        let homogeneity = terrain.classify_chunk(&coord);
+        if homogeneity == Homogeneity::Mixed {
+            // Keep going past the budget, to load the homogeneous chunks further out.
+            if mixed == budget {
+                continue;
+            }
+            mixed += 1;
+        }
+        chunks.push((coord, homogeneity));
+    }
+    return chunks;
+}
+
+/// Queued chunks in the order they should be generated: chunks wanted by
+/// higher priority loaders first, then the chunks closest to a loader.
+fn generation_order(
+    registry: &ChunkRegistry,
+    regions: &[LoaderRegion],
+    chunk_size: f32,
+) -> Vec<ChunkCoord> {
+    let mut pending: Vec<(ChunkCoord, i32, f32)> = registry
+        .iter()
+        .filter(|(_, entry)| entry.state == ChunkState::Queued)
+        .map(|(coord, _)| {
+            let mut priority = std::i32::MIN;
+            let mut dist = std::f32::INFINITY;
+            for region in regions.iter().filter(|region| region.contains(coord)) {
+                // Measured relative to the loader's chunk to stay precise far from chunk zero.
+                let center = coord.relative_to(&region.center).world_center(chunk_size);
+                let region_dist = (center - region.offset).magnitude();
+                if region.priority > priority
+                    || (region.priority == priority && region_dist < dist)
+                {
+                    priority = region.priority;
+                    dist = region_dist;
+                }
+            }
+            (*coord, priority, dist)
+        })
+        .collect();
+    // Ties are broken by coordinate, so the order doesn't depend on hash map
+    // iteration and replays generate the same chunks on the same steps.
+    pending.sort_by(|a, b| {
+        b.1.cmp(&a.1)
+            .then(a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal))
+            .then((a.0.x, a.0.y, a.0.z).cmp(&(b.0.x, b.0.y, b.0.z)))
+    });
+    return pending.into_iter().map(|(coord, _, _)| coord).collect();
+}
@@ -0,0 +1,248 @@
+use crate::{chunk_registry::ChunkCoord, input::{ActionBinding, GameBindings}};
+use amethyst::{
+    assets::{AssetStorage, Loader},
+    core::Time,
+    ecs::prelude::*,
+    input::InputEvent,
+    renderer::rendy::mesh::{Normal, Position, TexCoord},
+    shrev::EventChannel,
+    ui::{get_default_font, Anchor, FontAsset, UiText, UiTransform},
+};
+use log::info;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    mem::size_of,
+    time::Duration,
+};
+
+/// Number of recently generated chunks the averages are taken over.
+const RECENT_CHUNKS: usize = 64;
+
+#[derive(Deserialize)]
+pub struct ChunkStatsConfig {
+    /// Seconds between chunk statistics log lines. 0 disables logging.
+    pub log_interval: f32,
+    /// Whether the on-screen overlay starts visible.
+    pub show_overlay: bool,
+}
+
+impl Default for ChunkStatsConfig {
+    fn default() -> Self {
+        ChunkStatsConfig {
+            log_interval: 5.0,
+            show_overlay: false,
+        }
+    }
+}
+
+/// What it cost to stream in one chunk.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ChunkRecord {
+    /// Time spent sampling density.
+    pub generation: Duration,
+    /// Time spent running marching cubes.
+    pub meshing: Duration,
+    pub vertices: usize,
+    pub triangles: usize,
+    /// Bytes of density the chunk keeps.
+    pub density: usize,
+}
+
+impl ChunkRecord {
+    /// Estimated bytes held by the chunk's mesh and density.
+    pub fn memory(&self) -> usize {
+        let vertex_size = size_of::<Position>() + size_of::<Normal>() + size_of::<TexCoord>();
+        return self.vertices * vertex_size
+            + self.triangles * 3 * size_of::<u16>()
+            + self.density;
+    }
+}
+
+/// Streaming statistics, filled in by the `ChunkSystem`.
+#[derive(Default)]
+pub struct ChunkStats {
+    chunks: HashMap<ChunkCoord, ChunkRecord>,
+    recent: VecDeque<ChunkRecord>,
+    /// Chunks waiting to be generated.
+    pub queue_length: usize,
+    /// Chunks that have been generated, including ones without a mesh.
+    pub loaded_chunks: usize,
+    /// Chunks proven entirely solid or empty, which were never sampled.
+    pub skipped_chunks: usize,
+    /// Octree nodes proven entirely solid or empty, whose chunks are never looked at.
+    pub homogeneous_regions: usize,
+}
+
+impl ChunkStats {
+    pub fn record(&mut self, coord: ChunkCoord, record: ChunkRecord) {
+        self.chunks.insert(coord, record);
+        if self.recent.len() == RECENT_CHUNKS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(record);
+    }
+
+    pub fn remove(&mut self, coord: &ChunkCoord) {
+        self.chunks.remove(coord);
+    }
+
+    /// Average cost of the most recently generated chunks.
+    pub fn recent_average(&self) -> ChunkRecord {
+        let count = self.recent.len().max(1);
+        let mut total = ChunkRecord::default();
+        for record in &self.recent {
+            total.generation += record.generation;
+            total.meshing += record.meshing;
+            total.vertices += record.vertices;
+            total.triangles += record.triangles;
+            total.density += record.density;
+        }
+        return ChunkRecord {
+            generation: total.generation / count as u32,
+            meshing: total.meshing / count as u32,
+            vertices: total.vertices / count,
+            triangles: total.triangles / count,
+            density: total.density / count,
+        };
+    }
+
+    pub fn vertices(&self) -> usize {
+        return self.chunks.values().map(|record| record.vertices).sum();
+    }
+
+    pub fn triangles(&self) -> usize {
+        return self.chunks.values().map(|record| record.triangles).sum();
+    }
+
+    /// Estimated bytes held by the meshes and densities of every loaded chunk.
+    pub fn memory_estimate(&self) -> usize {
+        return self.chunks.values().map(ChunkRecord::memory).sum();
+    }
+
+    pub fn summary(&self) -> String {
+        let average = self.recent_average();
+        return format!(
+            "chunks: {} loaded, {} queued, {} skipped as homogeneous, {} homogeneous regions\n\
+             per chunk: {:.2}ms generation, {:.2}ms meshing, {} vertices\n\
+             total: {} vertices, {} triangles, {:.1} MiB",
+            self.loaded_chunks,
+            self.queue_length,
+            self.skipped_chunks,
+            self.homogeneous_regions,
+            average.generation.as_secs_f64() * 1000.0,
+            average.meshing.as_secs_f64() * 1000.0,
+            average.vertices,
+            self.vertices(),
+            self.triangles(),
+            self.memory_estimate() as f64 / (1024.0 * 1024.0),
+        );
+    }
+}
+
+/// Logs the `ChunkStats` periodically and shows them in an overlay toggled by `ToggleStats`.
+pub struct ChunkStatsSystem {
+    input_event_reader: Option<ReaderId<InputEvent<GameBindings>>>,
+    since_log: f32,
+    overlay: Option<Entity>,
+    show_overlay: bool,
+}
+
+impl ChunkStatsSystem {
+    pub fn new() -> Self {
+        ChunkStatsSystem {
+            input_event_reader: None,
+            since_log: 0.0,
+            overlay: None,
+            show_overlay: false,
+        }
+    }
+}
+
+impl<'s> System<'s> for ChunkStatsSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, Time>,
+        Read<'s, ChunkStats>,
+        ReadExpect<'s, ChunkStatsConfig>,
+        Read<'s, EventChannel<InputEvent<GameBindings>>>,
+        ReadExpect<'s, Loader>,
+        ReadExpect<'s, AssetStorage<FontAsset>>,
+        WriteStorage<'s, UiTransform>,
+        WriteStorage<'s, UiText>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            stats,
+            config,
+            input_event_channel,
+            loader,
+            fonts,
+            mut ui_transforms,
+            mut ui_texts,
+        ): Self::SystemData,
+    ) {
+        for e in input_event_channel.read(self.input_event_reader.as_mut().unwrap()) {
+            if let InputEvent::ActionPressed(ActionBinding::ToggleStats) = e {
+                self.show_overlay = !self.show_overlay;
+            }
+        }
+
+        if config.log_interval > 0.0 {
+            self.since_log += time.delta_seconds();
+            if self.since_log >= config.log_interval {
+                self.since_log = 0.0;
+                info!("{}", stats.summary().replace('\n', "; "));
+            }
+        }
+
+        let text = if self.show_overlay {
+            stats.summary()
+        } else {
+            String::new()
+        };
+        match self.overlay {
+            Some(overlay) => {
+                if let Some(ui_text) = ui_texts.get_mut(overlay) {
+                    ui_text.text = text;
+                }
+            }
+            None => {
+                let font = get_default_font(&loader, &fonts);
+                let overlay = entities
+                    .build_entity()
+                    .with(
+                        UiTransform::new(
+                            String::from("chunk_stats"),
+                            Anchor::TopLeft,
+                            Anchor::TopLeft,
+                            10.0,
+                            -10.0,
+                            1.0,
+                            700.0,
+                            80.0,
+                        ),
+                        &mut ui_transforms,
+                    )
+                    .with(
+                        UiText::new(font, text, [1.0, 1.0, 1.0, 1.0], 18.0),
+                        &mut ui_texts,
+                    )
+                    .build();
+                self.overlay = Some(overlay);
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        self.show_overlay = world.fetch::<ChunkStatsConfig>().show_overlay;
+        let mut ie = world.fetch_mut::<EventChannel<InputEvent<GameBindings>>>();
+        self.input_event_reader = Some(ie.register_reader());
+    }
+}
@@ -1,160 +1,241 @@
+use crate::chunk_registry::{ChunkBounds, ChunkCoord, ChunkRegistry, ChunkState};
+use crate::chunk_scheduler::{self, LoaderRegion};
+use crate::chunk_stats::{ChunkRecord, ChunkStats};
+use crate::floating_origin::FloatingOrigin;
+use crate::quantized_density::QuantizedDensity;
+use crate::states::RunState;
+use crate::terrain::Homogeneity;
+use crate::terrain_collision::CollisionConfig;
+use crate::terrain_materials::TerrainMaterials;
 use crate::Terrain;
-use std::collections::HashSet;
 use amethyst::{
-    assets::{self, AssetLoaderSystemData, AssetStorage, Handle},
+    assets::{AssetLoaderSystemData, Handle},
     core::{
         math::{Point3, Vector3},
-        Transform,
+        Parent, Transform,
     },
     ecs::prelude::*,
     renderer::{
-        mtl,
-        palette::LinSrgba,
         rendy::mesh::{Indices, MeshBuilder},
-        types::{self, MeshData},
+        types::MeshData,
         visibility::BoundingSphere,
-        Material, Mesh, Texture,
+        Material, Mesh,
     },
+    shrev::EventChannel,
 };
+use log::debug;
+use std::{sync::Arc, time::Instant};
 
-pub struct ChunkSystem {
-    pub chunk_posns: HashSet<(isize, isize, isize)>
+/// Maximum number of chunks sampled and meshed per run, so streaming doesn't stall a frame.
+/// Chunks proven homogeneous don't count, as they cost next to nothing.
+pub const MAX_CHUNKS_PER_RUN: usize = 8;
+
+/// Largest difference allowed between a chunk's kept density and the samples it was meshed from.
+pub const DENSITY_ERROR: f32 = 0.001;
+
+/// Published by the `ChunkSystem` whenever terrain appears or disappears.
+/// `entity` is `None` for chunks that generated no triangles. Chunks inside
+/// octree nodes proven entirely empty or solid aren't tracked one by one, and
+/// get no events.
+#[derive(Debug, Clone)]
+pub enum ChunkEvent {
+    Loaded {
+        coord: ChunkCoord,
+        entity: Option<Entity>,
+        bounds: ChunkBounds,
+    },
+    Unloaded {
+        coord: ChunkCoord,
+        entity: Option<Entity>,
+        bounds: ChunkBounds,
+    },
 }
-use crate::{components::*, visual_utils};
-use amethyst_physics::{
-    prelude::{PhysicsHandle, PhysicsRigidBodyTag, PhysicsShapeTag},
-    servers::{BodyMode, PhysicsWorld, RigidBodyDesc, ShapeDesc},
-};
+
+#[derive(Default)]
+pub struct ChunkSystem;
+
+use crate::components::*;
 
 impl<'s> System<'s> for ChunkSystem {
+    #[allow(clippy::type_complexity)]
     type SystemData = (
         Entities<'s>,
-        ReadExpect<'s, PhysicsWorld<f32>>,
-        AssetLoaderSystemData<'s, Mesh>,        
-        Read<'s, Terrain>,
+        AssetLoaderSystemData<'s, Mesh>,
+        Read<'s, Arc<Terrain>>,
+        Read<'s, FloatingOrigin>,
+        Read<'s, RunState>,
+        Write<'s, ChunkRegistry>,
+        Write<'s, EventChannel<ChunkEvent>>,
+        Write<'s, ChunkStats>,
         WriteStorage<'s, Chunk>,
         WriteStorage<'s, Transform>,
         WriteStorage<'s, Handle<Mesh>>,
         WriteStorage<'s, Handle<Material>>,
-        ReadStorage<'s, CharacterBody>,
+        ReadStorage<'s, ChunkLoader>,
+        ReadStorage<'s, Parent>,
         WriteStorage<'s, BoundingSphere>,
-        WriteStorage<'s, PhysicsHandle<PhysicsShapeTag>>,
-        WriteStorage<'s, PhysicsHandle<PhysicsRigidBodyTag>>,
-        ReadExpect<'s, assets::Loader>,
-        ReadExpect<'s, AssetStorage<Texture>>,
-        ReadExpect<'s, AssetStorage<mtl::Material>>,
-        ReadExpect<'s, mtl::MaterialDefaults>
+        ReadExpect<'s, TerrainMaterials>,
+        ReadExpect<'s, CollisionConfig>,
     );
 
     fn run(
         &mut self,
         (
             mut entities,
-            physics_world,
             mesh_loader,
             terrain,
+            origin,
+            run_state,
+            mut registry,
+            mut chunk_events,
+            mut stats,
             mut chunks,
             mut transforms,
             mut meshes,
             mut materials,
-            camera_boom_handles,
+            loaders,
+            parents,
             mut bounds,
-            mut physics_shape,
-            mut physics_rb,
-            loader,
-            tex,
-            mat,
-            mat_defaults
+            terrain_materials,
+            collision_config,
         ): Self::SystemData,
     ) {
-        let (cam_posn, _) = (&transforms, &camera_boom_handles).join().next().unwrap();
-        let cam_posn = cam_posn.translation();
+        if *run_state == RunState::Paused {
+            return;
+        }
         let chunk_size = (&*terrain).chunk_size();
-        for (e, transform, c) in (&*entities, &transforms, &chunks).join() {
-            let mut diff: Vector3<f32> = Vector3::new(0.0, 0.0, 0.0);
-            cam_posn.sub_to(transform.translation(), &mut diff);
-            let dist: f32 = diff.magnitude();
-            if dist > chunk_size * 10.0 {
-                println!("deleting chunk");
+        let regions: Vec<LoaderRegion> = (&transforms, &loaders, parents.maybe())
+            .join()
+            .map(|(transform, loader, parent)| {
+                // Global matrices aren't updated until after physics, so they are
+                // stale right after a floating origin shift; root entities don't need them.
+                let posn = if parent.is_some() {
+                    let global = transform.global_matrix();
+                    Vector3::new(global[(0, 3)], global[(1, 3)], global[(2, 3)])
+                } else {
+                    *transform.translation()
+                };
+                LoaderRegion::new(posn, loader, chunk_size, &origin)
+            })
+            .collect();
+
+        for coord in chunk_scheduler::chunks_to_unload(&registry, &regions) {
+            debug!("unloading chunk {:?}", coord);
+            let entity = registry.remove(&coord).and_then(|entry| entry.entity);
+            if let Some(e) = entity {
                 entities.delete(e).unwrap();
-                let chunk_posn = Vector3::new(
-                    (transform.translation().x / chunk_size) as isize,
-                    (transform.translation().y / chunk_size) as isize,
-                    (transform.translation().z / chunk_size) as isize,
-                );
-                self.chunk_posns.remove(&(chunk_posn.x, chunk_posn.y, chunk_posn.z));
             }
+            stats.remove(&coord);
+            chunk_events.single_write(ChunkEvent::Unloaded {
+                coord,
+                entity,
+                bounds: origin.chunk_bounds(&coord, chunk_size),
+            });
         }
-        
-        let posn = cam_posn;
-        let base_posn = Vector3::new(
-            (posn.x / chunk_size) as isize,
-            (posn.y / chunk_size) as isize,
-            (posn.z / chunk_size) as isize,
-        );
 
-        let range = 3;
-        for x in -range..(range + 1) {
-            for y in -range..(range + 1) {
-                for z in -range..(range + 1) {
-                    if !self.chunk_posns.contains(&(base_posn.x + x, base_posn.y + y, base_posn.z + z)) {
-                        let chunk_posn = Vector3::new(base_posn.x + x, base_posn.y + y, base_posn.z + z);
-                        create_chunk(
-                            &mut entities,
-                            &*physics_world,
-                            &*terrain,
-                            &chunk_posn,
-                            &mesh_loader,
-                            &*loader,
-                            &*tex,
-                            &*mat,
-                            &*mat_defaults,
-                            &mut meshes,
-                            &mut transforms,
-                            &mut chunks,
-                            &mut materials,
-                            &mut bounds,
-                            &mut physics_shape,
-                            &mut physics_rb,
-                        );
-                        self.chunk_posns.insert((base_posn.x + x, base_posn.y + y, base_posn.z + z));
+        chunk_scheduler::queue_regions(&mut registry, &terrain, &regions);
+
+        let next = chunk_scheduler::next_chunks(&registry, &regions, &terrain, MAX_CHUNKS_PER_RUN);
+        for (coord, homogeneity) in next {
+            let (state, entity) = match homogeneity {
+                Homogeneity::Empty => {
+                    stats.skipped_chunks += 1;
+                    (ChunkState::Empty, None)
+                }
+                Homogeneity::Solid => {
+                    stats.skipped_chunks += 1;
+                    (ChunkState::Solid, None)
+                }
+                Homogeneity::Mixed => {
+                    let entity = create_chunk(
+                        &mut entities,
+                        &*terrain,
+                        &*origin,
+                        &coord,
+                        &mesh_loader,
+                        &*terrain_materials,
+                        collision_config.mesh.uses_render_mesh(),
+                        &mut meshes,
+                        &mut transforms,
+                        &mut chunks,
+                        &mut materials,
+                        &mut bounds,
+                        &mut stats,
+                    );
+                    if entity.is_some() {
+                        (ChunkState::Ready, entity)
+                    } else {
+                        (ChunkState::Empty, None)
                     }
                 }
-            }
+            };
+            registry.set_entity(&coord, entity);
+            registry.set_state(&coord, state);
+
+            chunk_events.single_write(ChunkEvent::Loaded {
+                coord,
+                entity,
+                bounds: origin.chunk_bounds(&coord, chunk_size),
+            });
         }
+
+        stats.queue_length = registry.coords_in_state(ChunkState::Queued).len();
+        stats.loaded_chunks = registry
+            .iter()
+            .filter(|(coord, _)| registry.is_loaded(coord))
+            .count();
+        stats.homogeneous_regions = registry.homogeneous_nodes();
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_chunk(
     entities: &mut Entities,
-    physics_world: &PhysicsWorld<f32>,
     terrain: &Terrain,
-    chunk_posn: &Vector3<isize>,
+    origin: &FloatingOrigin,
+    chunk_posn: &ChunkCoord,
     mesh_loader: &AssetLoaderSystemData<Mesh>,
-    loader: &assets::Loader,
-    tex: &AssetStorage<types::Texture>,
-    mat: &AssetStorage<mtl::Material>,
-    mat_defaults: &mtl::MaterialDefaults,
+    terrain_materials: &TerrainMaterials,
+    keep_surface: bool,
     meshes: &mut WriteStorage<Handle<Mesh>>,
     transforms: &mut WriteStorage<Transform>,
     chunks: &mut WriteStorage<Chunk>,
     materials: &mut WriteStorage<Handle<Material>>,
     bounds: &mut WriteStorage<BoundingSphere>,
-    physics_shape: &mut WriteStorage<PhysicsHandle<PhysicsShapeTag>>,
-    physics_rb: &mut WriteStorage<PhysicsHandle<PhysicsRigidBodyTag>>,
-) {
-    let (indicies, posns, norms, coords) = terrain.get_chunk(chunk_posn).get_mesh_data();
-    if indicies.len() == 0 {
-        return;
-    }
-    let mut indicies_collision = Vec::new();
-    for i in 0..posns.len() / 3 {
-        indicies_collision.push(Point3::from_slice(&[i * 3, i * 3 + 1, i * 3 + 2]));
-    }
-    let mut points_collision = Vec::new();
-    for p in &posns {
-        points_collision.push(Point3::from_slice(&[p.0[0], p.0[1], p.0[2]]))
-    }
+    stats: &mut ChunkStats,
+) -> Option<Entity> {
+    let start = Instant::now();
+    let matrix = terrain.sample_chunk(chunk_posn);
+    let generation = start.elapsed();
+    let start = Instant::now();
+    let (indicies, posns, norms, coords) = terrain.mesh_chunk(&matrix).get_mesh_data();
+    let meshing = start.elapsed();
+    // Meshing reads every sample, so the density is only compacted once it's done.
+    let density = if indicies.is_empty() {
+        None
+    } else {
+        Some(QuantizedDensity::within(
+            &terrain.chunk_interior(&matrix),
+            DENSITY_ERROR,
+        ))
+    };
+    stats.record(
+        *chunk_posn,
+        ChunkRecord {
+            generation,
+            meshing,
+            vertices: posns.len(),
+            triangles: indicies.len() / 3,
+            density: density.as_ref().map_or(0, QuantizedDensity::memory),
+        },
+    );
+    let density = density?;
+    // Colliders built from the render mesh reuse these positions rather than meshing again.
+    let surface = if keep_surface {
+        Some(Arc::new(posns.iter().map(|p| p.0).collect()))
+    } else {
+        None
+    };
     let mesh = mesh_loader.load_from_data(
         MeshData(
             MeshBuilder::new()
@@ -165,39 +246,11 @@ fn create_chunk(
         ),
         (),
     );
-    /*
-    let shape = {
-        let desc = ShapeDesc::TriMesh {
-            indices: indicies_collision,
-            points: points_collision,
-        };
-        physics_world.shape_server().create(&desc)
-    };
-    */
-    let mat = visual_utils::create_material(
-        loader,
-        tex,
-        mat,
-        mat_defaults,
-        LinSrgba::new(0.7188, 0.1578, 0.0, 1.0),
-        0.0, // Metallic
-        1.0, // Roughness
-    );
+    let mat = terrain_materials.for_chunk(chunk_posn, terrain.chunk_size());
 
     let mut transform = Transform::default();
-    transform.set_translation_xyz(
-        chunk_posn.x as f32 * terrain.chunk_size(),
-        chunk_posn.y as f32 * terrain.chunk_size(),
-        chunk_posn.z as f32 * terrain.chunk_size(),
-    );
-    /*
-    let rb = {
-        let mut rb_desc = RigidBodyDesc::default();
-        rb_desc.mode = BodyMode::Static;
-        physics_world.rigid_body_server().create(&rb_desc)
-    };
-    */
-    entities
+    transform.set_translation(origin.chunk_origin(chunk_posn, terrain.chunk_size()));
+    let entity = entities
         .build_entity()
         .with(mesh, meshes)
         .with(mat, materials)
@@ -213,10 +266,14 @@ fn create_chunk(
             bounds,
         )
         .with(transform, transforms)
-        /*
-        .with(shape, physics_shape)
-        .with(rb, physics_rb)
-        */
-        .with(Chunk, chunks)
+        .with(
+            Chunk {
+                coord: *chunk_posn,
+                surface,
+                density: Arc::new(density),
+            },
+            chunks,
+        )
         .build();
+    return Some(entity);
 }
@@ -1,5 +1,8 @@
-use amethyst::ecs::{storage::{NullStorage, VecStorage}, Component};
+use amethyst::ecs::{storage::{DenseVecStorage, NullStorage, VecStorage}, Component};
 use amethyst::core::math::Vector3;
+use std::sync::Arc;
+
+use crate::{chunk_registry::ChunkCoord, quantized_density::QuantizedDensity};
 
 /// Camera Boom handle tag, used to identify the camera boom handle entity
 #[derive(Default)]
@@ -9,6 +12,22 @@ impl Component for CameraBoomHandle {
     type Storage = NullStorage<Self>;
 }
 
+/// Moves a camera parented to a `CameraBoomHandle` along its boom. See `CameraBoomSystem`.
+#[derive(Debug, Copy, Clone)]
+pub struct CameraBoom {
+    pub third_person: bool,
+    /// Boom length zoomed to in third person.
+    pub length: f32,
+    /// Current distance behind the handle, after smoothing and collision.
+    pub distance: f32,
+    /// Current sideways offset from the handle.
+    pub shoulder: f32,
+}
+
+impl Component for CameraBoom {
+    type Storage = DenseVecStorage<Self>;
+}
+
 /// Tag used to identify the character body entity.
 #[derive(Default)]
 pub struct CharacterBody;
@@ -17,10 +36,66 @@ impl Component for CharacterBody {
     type Storage = NullStorage<Self>;
 }
 
-/// Tag used to identify Chunk entities
+/// How a character moves over terrain. Angles are in degrees, distances in world units.
+#[derive(Debug, Copy, Clone)]
+pub struct CharacterController {
+    /// Steepest slope the character can stand on; steeper ground is slid down.
+    pub max_slope_angle: f32,
+    /// Highest ledge the character steps onto without jumping.
+    pub step_height: f32,
+    /// Furthest the character is pulled down to stay on descending ground.
+    pub snap_distance: f32,
+    /// Distance from the body's center down to its feet.
+    pub feet_offset: f32,
+    /// Horizontal radius of the body.
+    pub radius: f32,
+}
+
+impl Component for CharacterController {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// A free-flying camera detached from the character. See `SpectatorSystem`.
+#[derive(Debug, Copy, Clone)]
+pub struct Spectator {
+    /// Flying speed, in world units per second.
+    pub speed: f32,
+}
+
+impl Component for Spectator {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Tag for a spectator flying along a camera path, which mouse and stick input leave alone.
 #[derive(Default)]
-pub struct Chunk;
+pub struct OnCameraPath;
 
-impl Component for Chunk {
+impl Component for OnCameraPath {
     type Storage = NullStorage<Self>;
 }
+
+/// Identifies Chunk entities and the chunk coordinate they were generated for.
+pub struct Chunk {
+    pub coord: ChunkCoord,
+    /// Vertex positions of the rendered mesh, relative to the chunk, three per triangle.
+    /// Only kept when colliders are built from the render mesh.
+    pub surface: Option<Arc<Vec<[f32; 3]>>>,
+    /// Density within the chunk, kept quantized to within `DENSITY_ERROR` of the samples.
+    pub density: Arc<QuantizedDensity>,
+}
+
+impl Component for Chunk {
+    type Storage = VecStorage<Self>;
+}
+
+/// Keeps the chunks within `radius` chunks of the entity loaded.
+/// Chunks wanted by loaders with a higher `priority` are generated first.
+#[derive(Debug, Copy, Clone)]
+pub struct ChunkLoader {
+    pub radius: u32,
+    pub priority: i32,
+}
+
+impl Component for ChunkLoader {
+    type Storage = DenseVecStorage<Self>;
+}
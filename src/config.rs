@@ -0,0 +1,52 @@
+use amethyst::Error;
+use ron::from_str;
+use serde::de::DeserializeOwned;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads a RON file into `T`.
+pub fn load_ron<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    return Ok(from_str(&fs::read_to_string(path)?)?);
+}
+
+/// Polls a file's modification time to notice when it has been edited.
+pub struct FileWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_check: Instant,
+}
+
+impl FileWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        FileWatcher {
+            path,
+            modified,
+            last_check: Instant::now(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+
+    /// Returns true once each time the file is modified.
+    /// The file system is checked at most once per `CHECK_INTERVAL`.
+    pub fn changed(&mut self) -> bool {
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified != self.modified {
+            self.modified = modified;
+            return true;
+        }
+        return false;
+    }
+}
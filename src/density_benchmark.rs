@@ -0,0 +1,151 @@
+use crate::{
+    chunk_registry::ChunkCoord,
+    chunk_system::DENSITY_ERROR,
+    marching_cubes::CUTOFF,
+    matrix_3d::Matrix3D,
+    quantized_density::{Precision, QuantizedDensity},
+    terrain::{Homogeneity, Terrain},
+};
+use std::time::{Duration, Instant};
+
+/// Interpolation factors compared against exact evaluation.
+const FACTORS: [usize; 3] = [2, 3, 4];
+/// Chunks along each horizontal axis of the benchmarked area.
+const AREA: i64 = 8;
+/// Chunk rows searched for the surface.
+const ROWS: std::ops::Range<i64> = -12..6;
+
+/// How far an interpolated chunk strays from the exact one.
+#[derive(Default)]
+struct SampleError {
+    max: f32,
+    sum_squares: f64,
+    flipped: usize,
+    samples: usize,
+}
+
+impl SampleError {
+    fn add(&mut self, exact: &Matrix3D<f32>, approx: &Matrix3D<f32>) {
+        for (posn, e) in exact.iter() {
+            let a = approx[posn];
+            let diff = (e - a).abs();
+            self.max = self.max.max(diff);
+            self.sum_squares += (diff as f64) * (diff as f64);
+            if (*e < CUTOFF) != (a < CUTOFF) {
+                self.flipped += 1;
+            }
+            self.samples += 1;
+        }
+    }
+
+    fn rms(&self) -> f64 {
+        return (self.sum_squares / self.samples.max(1) as f64).sqrt();
+    }
+
+    /// Percentage of samples that ended up on the other side of the surface.
+    fn flipped_percent(&self) -> f64 {
+        return self.flipped as f64 * 100.0 / self.samples.max(1) as f64;
+    }
+}
+
+/// Samples the surface chunks of a fixed area exactly and with each of the
+/// `FACTORS`, printing throughput and error against the exact densities,
+/// then how small the exact densities get once quantized.
+/// Run with `--bench-density`.
+pub fn run(terrain: &Terrain) {
+    let mut chunks = vec![];
+    for x in 0..AREA {
+        for z in 0..AREA {
+            for y in ROWS {
+                let coord = ChunkCoord::new(x, y, z);
+                if terrain.classify_chunk(&coord) == Homogeneity::Mixed {
+                    chunks.push(coord);
+                }
+            }
+        }
+    }
+    println!("density benchmark over {} surface chunks", chunks.len());
+
+    let (exact, exact_time) = sample_all(&terrain.clone().with_interpolation(1), &chunks);
+    println!(
+        "exact:    {:>8.1} chunks/s",
+        chunks.len() as f64 / exact_time.as_secs_f64()
+    );
+
+    for factor in FACTORS.iter() {
+        let (approx, time) = sample_all(&terrain.clone().with_interpolation(*factor), &chunks);
+        let mut error = SampleError::default();
+        for (e, a) in exact.iter().zip(approx.iter()) {
+            error.add(e, a);
+        }
+        println!(
+            "factor {}: {:>8.1} chunks/s ({:.2}x), max error {:.4}, rms {:.4}, {:.3}% of samples flipped sides",
+            factor,
+            chunks.len() as f64 / time.as_secs_f64(),
+            exact_time.as_secs_f64() / time.as_secs_f64(),
+            error.max,
+            error.rms(),
+            error.flipped_percent(),
+        );
+    }
+
+    let compact: usize = exact
+        .iter()
+        .map(|matrix| {
+            let mut matrix = matrix.clone();
+            matrix.compact();
+            matrix.memory()
+        })
+        .sum();
+    println!("storage:  {:>8} KiB compacted", compact / 1024);
+    for precision in &[Precision::Bits8, Precision::Bits16] {
+        let mut memory = 0;
+        let mut error = SampleError::default();
+        let mut max_error: f32 = 0.0;
+        for matrix in &exact {
+            let quantized = QuantizedDensity::new(matrix, *precision);
+            memory += quantized.memory();
+            max_error = max_error.max(quantized.max_error());
+            error.add(matrix, &quantized.to_matrix());
+        }
+        println!(
+            "{:?}:   {:>8} KiB quantized, max error {:.4} (bound {:.4}), {:.3}% of samples flipped sides",
+            precision,
+            memory / 1024,
+            error.max,
+            max_error,
+            error.flipped_percent(),
+        );
+    }
+
+    // Each chunk as the `ChunkSystem` keeps it: at the smallest precision meeting
+    // the error bound, or exact if none does.
+    let mut memory = 0;
+    let mut counts = [0; 3];
+    for matrix in &exact {
+        let quantized = QuantizedDensity::within(&terrain.chunk_interior(matrix), DENSITY_ERROR);
+        memory += quantized.memory();
+        match quantized.precision() {
+            Precision::Bits8 => counts[0] += 1,
+            Precision::Bits16 => counts[1] += 1,
+            Precision::Exact => counts[2] += 1,
+        }
+    }
+    println!(
+        "within {}: {:>8} KiB, {} chunks at 8 bits, {} at 16 bits, {} exact",
+        DENSITY_ERROR,
+        memory / 1024,
+        counts[0],
+        counts[1],
+        counts[2],
+    );
+}
+
+fn sample_all(terrain: &Terrain, chunks: &[ChunkCoord]) -> (Vec<Matrix3D<f32>>, Duration) {
+    let start = Instant::now();
+    let matrices = chunks
+        .iter()
+        .map(|coord| terrain.sample_chunk(coord))
+        .collect();
+    return (matrices, start.elapsed());
+}
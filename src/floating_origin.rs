@@ -0,0 +1,132 @@
+use crate::{
+    chunk_registry::{ChunkBounds, ChunkCoord},
+    components::{CharacterBody, Spectator},
+    Terrain,
+};
+use amethyst::{
+    core::{math::Vector3, Parent, Transform},
+    ecs::prelude::*,
+};
+use amethyst_physics::prelude::*;
+use log::debug;
+use std::sync::Arc;
+
+/// Distance from the origin, in world units, the character may travel before the world is shifted.
+const SHIFT_THRESHOLD: f32 = 512.0;
+
+/// Which absolute chunk the world-space origin sits at.
+///
+/// Transforms are `f32` and lose precision far from zero, so once the character
+/// gets further than `threshold` from the origin, the `FloatingOriginSystem` moves
+/// every entity back by a whole number of chunks and records that shift here.
+/// `ChunkCoord`s stay absolute, so terrain generation doesn't notice.
+#[derive(Debug, Copy, Clone)]
+pub struct FloatingOrigin {
+    pub chunk: ChunkCoord,
+    pub threshold: f32,
+}
+
+impl Default for FloatingOrigin {
+    fn default() -> Self {
+        FloatingOrigin {
+            chunk: ChunkCoord::default(),
+            threshold: SHIFT_THRESHOLD,
+        }
+    }
+}
+
+impl FloatingOrigin {
+    /// The absolute coordinate of the chunk containing the world position `posn`.
+    pub fn chunk_at(&self, posn: &Vector3<f32>, chunk_size: f32) -> ChunkCoord {
+        let local = ChunkCoord::from_world(posn, chunk_size);
+        return local.offset(self.chunk.x, self.chunk.y, self.chunk.z);
+    }
+
+    /// World position of the minimum corner of the chunk at `coord`.
+    pub fn chunk_origin(&self, coord: &ChunkCoord, chunk_size: f32) -> Vector3<f32> {
+        return coord.relative_to(&self.chunk).world_origin(chunk_size);
+    }
+
+    /// Absolute position, in world units counted from chunk zero, of the world position `posn`.
+    pub fn absolute(&self, posn: &Vector3<f32>, chunk_size: f32) -> Vector3<f64> {
+        let origin = Vector3::new(self.chunk.x as f64, self.chunk.y as f64, self.chunk.z as f64)
+            .scale(chunk_size as f64);
+        return origin + Vector3::new(posn.x as f64, posn.y as f64, posn.z as f64);
+    }
+
+    /// World position of the absolute position `posn`; the inverse of `absolute`.
+    pub fn relative(&self, posn: &Vector3<f64>, chunk_size: f32) -> Vector3<f32> {
+        let origin = Vector3::new(self.chunk.x as f64, self.chunk.y as f64, self.chunk.z as f64)
+            .scale(chunk_size as f64);
+        let local = posn - origin;
+        return Vector3::new(local.x as f32, local.y as f32, local.z as f32);
+    }
+
+    pub fn chunk_bounds(&self, coord: &ChunkCoord, chunk_size: f32) -> ChunkBounds {
+        return ChunkBounds::new(&coord.relative_to(&self.chunk), chunk_size);
+    }
+}
+
+/// Shifts the world back toward zero when the character, or the spectator if there
+/// is one, strays too far from it.
+/// Runs before the other pre-physics systems, so they all see the shifted world.
+pub struct FloatingOriginSystem;
+
+impl<'s> System<'s> for FloatingOriginSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        Write<'s, FloatingOrigin>,
+        Read<'s, Arc<Terrain>>,
+        ReadExpect<'s, PhysicsWorld<f32>>,
+        ReadStorage<'s, CharacterBody>,
+        ReadStorage<'s, Spectator>,
+        ReadStorage<'s, Parent>,
+        ReadStorage<'s, PhysicsHandle<PhysicsRigidBodyTag>>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            mut origin,
+            terrain,
+            physics_world,
+            character_bodies,
+            spectators,
+            parents,
+            rigid_bodies,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        // A spectator can fly far from the character; precision matters where it looks.
+        let spectator = (&transforms, &spectators).join().next();
+        let character = (&transforms, &character_bodies).join().next();
+        let posn = match (spectator, character) {
+            (Some((transform, _)), _) => *transform.translation(),
+            (None, Some((transform, _))) => *transform.translation(),
+            (None, None) => return,
+        };
+        if posn.magnitude() < origin.threshold {
+            return;
+        }
+
+        // Shift by whole chunks, so chunk meshes still line up with the chunk grid.
+        let chunk_size = terrain.chunk_size();
+        let shift_chunks = ChunkCoord::from_world(&posn, chunk_size);
+        let shift = shift_chunks.world_origin(chunk_size);
+        origin.chunk = origin
+            .chunk
+            .offset(shift_chunks.x, shift_chunks.y, shift_chunks.z);
+        debug!("shifting world origin to chunk {:?}", origin.chunk);
+
+        // Children move with their parents.
+        for (transform, _) in (&mut transforms, !&parents).join() {
+            transform.prepend_translation(-shift);
+        }
+        for (transform, rb, _) in (&transforms, &rigid_bodies, !&parents).join() {
+            physics_world
+                .rigid_body_server()
+                .set_transform(rb.get(), transform.isometry());
+        }
+    }
+}
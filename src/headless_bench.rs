@@ -0,0 +1,197 @@
+use crate::{
+    camera_path::CameraPath,
+    chunk_registry::{ChunkCoord, ChunkRegistry, ChunkState},
+    chunk_scheduler::{self, LoaderRegion},
+    chunk_system::MAX_CHUNKS_PER_RUN,
+    components::ChunkLoader,
+    floating_origin::FloatingOrigin,
+    terrain::{Homogeneity, Terrain},
+};
+use amethyst::Error;
+use serde::Serialize;
+use std::{collections::HashMap, fs, path::Path, time::Instant};
+
+/// Simulated frames per second of path time.
+const FRAME_RATE: f32 = 60.0;
+/// Frames run after the end of the path at most, waiting for the queue to drain.
+const MAX_DRAIN_FRAMES: usize = 100_000;
+
+#[derive(Serialize)]
+struct FrameReport {
+    frame: usize,
+    /// Chunks generated, with or without a mesh.
+    loaded: usize,
+    /// Chunks waiting to be generated.
+    queued: usize,
+    /// Chunks sampled and meshed this frame.
+    generated: usize,
+    /// Chunks proven homogeneous this frame, so not sampled.
+    skipped: usize,
+    /// Octree nodes proven homogeneous, each standing in for all of its chunks.
+    homogeneous_regions: usize,
+    unloaded: usize,
+    /// Time spent streaming this frame.
+    seconds: f64,
+}
+
+#[derive(Serialize, Default)]
+struct Percentiles {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Percentiles {
+    fn of(mut values: Vec<f64>) -> Self {
+        if values.is_empty() {
+            return Percentiles::default();
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let rank = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+        return Percentiles {
+            p50: rank(0.5),
+            p90: rank(0.9),
+            p99: rank(0.99),
+            max: values[values.len() - 1],
+        };
+    }
+}
+
+#[derive(Serialize)]
+struct Report {
+    seed: u64,
+    path: String,
+    frame_seconds: f32,
+    max_chunks_per_frame: usize,
+    generated_chunks: usize,
+    /// Chunks sampled and meshed per second of streaming time.
+    chunks_per_second: f64,
+    /// Time to sample and mesh one chunk, in milliseconds.
+    generation_ms: Percentiles,
+    /// Frames a chunk waited between being queued and generated.
+    wait_frames: Percentiles,
+    /// Peak resident memory of the process, from /proc; absent elsewhere.
+    peak_memory_kib: Option<u64>,
+    frames: Vec<FrameReport>,
+}
+
+/// Streams chunks around a loader flying along the camera path in `path_file`,
+/// a fixed step per frame, with the same scheduling as the `ChunkSystem` but no
+/// window, entities or rendering. Prints a JSON report to stdout.
+/// Run with `--headless-bench [camera path]`.
+pub fn run(terrain: &Terrain, seed: u64, path_file: &Path) -> Result<(), Error> {
+    let curves = CameraPath::load(path_file)?
+        .curves()
+        .ok_or_else(|| Error::from_string(format!("{:?} has no keys", path_file)))?;
+    let chunk_size = terrain.chunk_size();
+    let origin = FloatingOrigin::default();
+    let loader = ChunkLoader {
+        radius: crate::LOAD_RADIUS,
+        priority: 0,
+    };
+    let mut registry = ChunkRegistry::default();
+    // The frame each pending chunk was first seen queued on.
+    let mut queued_on: HashMap<ChunkCoord, usize> = HashMap::new();
+    let mut generation_ms = vec![];
+    let mut wait_frames = vec![];
+    let mut frames = vec![];
+    let path_frames = (curves.duration() * FRAME_RATE).ceil() as usize;
+
+    for frame in 0..(path_frames + MAX_DRAIN_FRAMES) {
+        let (posn, _) = curves.sample(frame as f32 / FRAME_RATE);
+        let regions = [LoaderRegion::new(
+            origin.relative(&posn, chunk_size),
+            &loader,
+            chunk_size,
+            &origin,
+        )];
+        let start = Instant::now();
+
+        let unload = chunk_scheduler::chunks_to_unload(&registry, &regions);
+        for coord in &unload {
+            registry.remove(coord);
+            queued_on.remove(coord);
+        }
+        chunk_scheduler::queue_regions(&mut registry, terrain, &regions);
+        for (coord, entry) in registry.iter() {
+            if entry.state == ChunkState::Queued {
+                queued_on.entry(*coord).or_insert(frame);
+            }
+        }
+
+        let mut generated = 0;
+        let mut skipped = 0;
+        let next = chunk_scheduler::next_chunks(&registry, &regions, terrain, MAX_CHUNKS_PER_RUN);
+        for (coord, homogeneity) in next {
+            let state = match homogeneity {
+                Homogeneity::Empty => {
+                    skipped += 1;
+                    ChunkState::Empty
+                }
+                Homogeneity::Solid => {
+                    skipped += 1;
+                    ChunkState::Solid
+                }
+                Homogeneity::Mixed => {
+                    generated += 1;
+                    let chunk_start = Instant::now();
+                    let matrix = terrain.sample_chunk(&coord);
+                    let (indices, ..) = terrain.mesh_chunk(&matrix).get_mesh_data();
+                    generation_ms.push(chunk_start.elapsed().as_secs_f64() * 1000.0);
+                    if let Some(queued) = queued_on.remove(&coord) {
+                        wait_frames.push((frame - queued) as f64);
+                    }
+                    if indices.is_empty() {
+                        ChunkState::Empty
+                    } else {
+                        ChunkState::Ready
+                    }
+                }
+            };
+            queued_on.remove(&coord);
+            registry.set_state(&coord, state);
+        }
+
+        let queued = registry.coords_in_state(ChunkState::Queued).len();
+        frames.push(FrameReport {
+            frame,
+            loaded: registry
+                .iter()
+                .filter(|(coord, _)| registry.is_loaded(coord))
+                .count(),
+            queued,
+            generated,
+            skipped,
+            homogeneous_regions: registry.homogeneous_nodes(),
+            unloaded: unload.len(),
+            seconds: start.elapsed().as_secs_f64(),
+        });
+        if frame >= path_frames && queued == 0 {
+            break;
+        }
+    }
+
+    let streaming_seconds: f64 = frames.iter().map(|frame| frame.seconds).sum();
+    let report = Report {
+        seed,
+        path: path_file.display().to_string(),
+        frame_seconds: 1.0 / FRAME_RATE,
+        max_chunks_per_frame: MAX_CHUNKS_PER_RUN,
+        generated_chunks: generation_ms.len(),
+        chunks_per_second: generation_ms.len() as f64 / streaming_seconds.max(std::f64::EPSILON),
+        generation_ms: Percentiles::of(generation_ms),
+        wait_frames: Percentiles::of(wait_frames),
+        peak_memory_kib: peak_memory_kib(),
+        frames,
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    return Ok(());
+}
+
+/// The `VmHWM` line of /proc/self/status, if there is one.
+fn peak_memory_kib() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    return line.split_whitespace().nth(1)?.parse().ok();
+}
@@ -0,0 +1,94 @@
+use crate::config;
+use amethyst::{
+    input::{BindingTypes, Bindings},
+    Error,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::Path};
+
+/// Analog inputs, each combining keys, mouse buttons and gamepad sticks.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AxisBinding {
+    /// Positive forwards.
+    MoveForward,
+    /// Positive to the right.
+    MoveRight,
+    /// Positive to the right. Mouse movement also turns the camera.
+    LookYaw,
+    /// Positive upwards.
+    LookPitch,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ActionBinding {
+    Jump,
+    Descend,
+    Sprint,
+    ToggleFly,
+    ToggleStats,
+    /// Switches between first and third person.
+    ToggleCamera,
+    ZoomIn,
+    ZoomOut,
+    /// Detaches the camera from the character to fly freely, or returns it.
+    ToggleSpectator,
+    /// Flies the spectator along the camera path, or stops it.
+    PlayCameraPath,
+    /// Adds where the camera is to the end of the recorded camera path.
+    RecordKeyframe,
+}
+
+impl fmt::Display for AxisBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{:?}", self);
+    }
+}
+
+impl fmt::Display for ActionBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{:?}", self);
+    }
+}
+
+#[derive(Debug)]
+pub struct GameBindings;
+
+impl BindingTypes for GameBindings {
+    type Axis = AxisBinding;
+    type Action = ActionBinding;
+}
+
+/// Loads the default bindings from `defaults`, then replaces every axis and action
+/// that `overrides` binds, if that file exists. An override that clashes with
+/// another action's buttons is skipped with a warning, keeping the default.
+pub fn load_bindings(defaults: &Path, overrides: &Path) -> Result<Bindings<GameBindings>, Error> {
+    let mut bindings: Bindings<GameBindings> = config::load_ron(defaults)?;
+    if !overrides.exists() {
+        return Ok(bindings);
+    }
+    info!("loading input overrides from {:?}", overrides);
+    let user: Bindings<GameBindings> = config::load_ron(overrides)?;
+
+    // Each override is tried on a copy, which only replaces the bindings if it fits.
+    for id in user.axes() {
+        let mut updated = bindings.clone();
+        updated.remove_axis(id);
+        match updated.insert_axis(id.clone(), user.axis(id).unwrap().clone()) {
+            Ok(_) => bindings = updated,
+            Err(e) => warn!("ignoring binding for axis {}: {}", id, e),
+        }
+    }
+    for id in user.actions() {
+        let mut updated = bindings.clone();
+        updated.remove_action(id);
+        let inserted = user
+            .action_bindings(id)
+            .try_for_each(|combo| updated.insert_action_binding(id.clone(), combo.iter().cloned()));
+        match inserted {
+            Ok(()) => bindings = updated,
+            Err(e) => warn!("ignoring bindings for action {}: {}", id, e),
+        }
+    }
+    Ok(bindings)
+}
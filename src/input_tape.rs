@@ -0,0 +1,264 @@
+use crate::{
+    components::CharacterBody,
+    input::{ActionBinding, AxisBinding, GameBindings},
+    states::RunState,
+};
+use amethyst::{
+    core::Transform,
+    ecs::prelude::*,
+    input::{InputEvent, InputHandler},
+    shrev::EventChannel,
+    Error,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{BufWriter, Write as _},
+    path::Path,
+};
+
+/// Axes and held actions the character and its camera read.
+const AXES: [AxisBinding; 4] = [
+    AxisBinding::MoveForward,
+    AxisBinding::MoveRight,
+    AxisBinding::LookYaw,
+    AxisBinding::LookPitch,
+];
+const HELD_ACTIONS: [ActionBinding; 3] = [
+    ActionBinding::Jump,
+    ActionBinding::Descend,
+    ActionBinding::Sprint,
+];
+
+/// The input the `CameraMotionSystem` and `CharacterMotionControllerSystem` act on
+/// during one physics step. Filled in by the `InputTapeSystem`, from live input or a tape.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StepInput {
+    /// Mouse motion and action presses since the previous step.
+    pub events: Vec<InputEvent<GameBindings>>,
+    pub axes: Vec<(AxisBinding, f32)>,
+    pub held: Vec<ActionBinding>,
+}
+
+impl StepInput {
+    fn capture(events: Vec<InputEvent<GameBindings>>, input: &InputHandler<GameBindings>) -> Self {
+        StepInput {
+            events,
+            axes: AXES
+                .iter()
+                .filter_map(|axis| {
+                    let value = input.axis_value(axis).unwrap_or(0.0);
+                    if value == 0.0 {
+                        return None;
+                    }
+                    return Some((axis.clone(), value));
+                })
+                .collect(),
+            held: HELD_ACTIONS
+                .iter()
+                .filter(|action| input.action_is_down(action).unwrap_or(false))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn axis_value(&self, axis: &AxisBinding) -> f32 {
+        return self
+            .axes
+            .iter()
+            .find(|(a, _)| a == axis)
+            .map(|(_, value)| *value)
+            .unwrap_or(0.0);
+    }
+
+    pub fn action_is_down(&self, action: &ActionBinding) -> bool {
+        return self.held.contains(action);
+    }
+}
+
+/// First line of a tape file.
+#[derive(Serialize, Deserialize)]
+struct TapeHeader {
+    seed: u64,
+}
+
+/// One line of a tape file per physics step played.
+#[derive(Serialize, Deserialize)]
+struct TapeStep {
+    step: u64,
+    input: StepInput,
+    /// Where the character was at the start of the step, to notice a replay diverging.
+    posn: Option<[f32; 3]>,
+}
+
+/// Recorded input, read from a file written with `--record`.
+pub struct Tape {
+    /// Seed of the world the input was recorded in.
+    pub seed: u64,
+    steps: VecDeque<TapeStep>,
+}
+
+impl Tape {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.lines();
+        let header: TapeHeader = ron::from_str(lines.next().unwrap_or(""))?;
+        let mut steps = VecDeque::new();
+        for line in lines {
+            steps.push_back(ron::from_str(line)?);
+        }
+        info!("loaded {} steps of input from {:?}", steps.len(), path);
+        return Ok(Tape {
+            seed: header.seed,
+            steps,
+        });
+    }
+}
+
+enum Mode {
+    Live,
+    Record(BufWriter<File>),
+    Replay(Tape),
+}
+
+/// Fills in the `StepInput` at the start of every physics step while playing.
+///
+/// Recording writes each step's input to a tape, a line at a time so a crash
+/// loses nothing. Replaying feeds the tape back in place of live input, and
+/// warns if the character ends up anywhere other than where it was recorded.
+/// Steps are counted from the start of play, at the fixed physics rate, so a
+/// replay of the same seed with the same configs retraces the recording exactly.
+/// The spectator isn't recorded, so a recording should stay with the character.
+pub struct InputTapeSystem {
+    mode: Mode,
+    step: u64,
+    diverged: bool,
+    input_event_reader: Option<ReaderId<InputEvent<GameBindings>>>,
+}
+
+impl InputTapeSystem {
+    fn new(mode: Mode) -> Self {
+        InputTapeSystem {
+            mode,
+            step: 0,
+            diverged: false,
+            input_event_reader: None,
+        }
+    }
+
+    pub fn live() -> Self {
+        return InputTapeSystem::new(Mode::Live);
+    }
+
+    /// Records to a new tape at `path`, for a world generated from `seed`.
+    pub fn record(path: &Path, seed: u64) -> Result<Self, Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{}", ron::ser::to_string(&TapeHeader { seed })?)?;
+        file.flush()?;
+        info!("recording input to {:?}", path);
+        return Ok(InputTapeSystem::new(Mode::Record(file)));
+    }
+
+    pub fn replay(tape: Tape) -> Self {
+        return InputTapeSystem::new(Mode::Replay(tape));
+    }
+}
+
+impl<'s> System<'s> for InputTapeSystem {
+    type SystemData = (
+        Read<'s, RunState>,
+        Read<'s, EventChannel<InputEvent<GameBindings>>>,
+        Read<'s, InputHandler<GameBindings>>,
+        ReadStorage<'s, CharacterBody>,
+        ReadStorage<'s, Transform>,
+        Write<'s, StepInput>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            run_state,
+            input_event_channel,
+            input,
+            character_bodies,
+            transforms,
+            mut step_input,
+        ): Self::SystemData,
+    ) {
+        let events: Vec<InputEvent<GameBindings>> = input_event_channel
+            .read(self.input_event_reader.as_mut().unwrap())
+            .filter(|e| match e {
+                InputEvent::MouseMoved { .. } | InputEvent::ActionPressed(_) => true,
+                _ => false,
+            })
+            .cloned()
+            .collect();
+        if *run_state != RunState::Playing {
+            *step_input = StepInput::default();
+            return;
+        }
+
+        let posn = (&transforms, &character_bodies)
+            .join()
+            .next()
+            .map(|(transform, _)| {
+                let posn = transform.translation();
+                [posn.x, posn.y, posn.z]
+            });
+        let step = self.step;
+        self.step += 1;
+        let diverged = &mut self.diverged;
+
+        let replayed = match &mut self.mode {
+            Mode::Live => None,
+            Mode::Record(file) => {
+                let recorded = TapeStep {
+                    step,
+                    input: StepInput::capture(events.clone(), &input),
+                    posn,
+                };
+                match ron::ser::to_string(&recorded) {
+                    Ok(line) => {
+                        if let Err(e) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+                            warn!("could not record input: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("could not record input: {}", e),
+                }
+                Some(recorded.input)
+            }
+            Mode::Replay(tape) => tape.steps.pop_front().map(|recorded| {
+                if recorded.posn != posn && !*diverged {
+                    *diverged = true;
+                    warn!(
+                        "replay diverged at step {}: character at {:?}, recorded at {:?}",
+                        step, posn, recorded.posn
+                    );
+                }
+                recorded.input
+            }),
+        };
+
+        *step_input = match replayed {
+            Some(input) => input,
+            None => {
+                if let Mode::Replay(_) = self.mode {
+                    info!(
+                        "replay finished after {} steps with the character at {:?}",
+                        step, posn
+                    );
+                    self.mode = Mode::Live;
+                }
+                StepInput::capture(events, &input)
+            }
+        };
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        let mut ie = world.fetch_mut::<EventChannel<InputEvent<GameBindings>>>();
+        self.input_event_reader = Some(ie.register_reader());
+    }
+}
@@ -5,14 +5,14 @@ use amethyst::{
         transform::{Transform, TransformBundle},
         Parent,
     },
-    input::{InputBundle, StringBindings},
+    input::InputBundle,
+    ui::{RenderUi, UiBundle},
     prelude::*,
     renderer::{
         self,
-        camera::Camera,
         light,
         palette::{LinSrgba, Srgb},
-        plugins::{RenderShaded3D, RenderToWindow},
+        plugins::{RenderDebugLines, RenderShaded3D, RenderToWindow},
         rendy::mesh::MeshBuilder,
         types,
         types::{Mesh, MeshData},
@@ -23,51 +23,54 @@ use amethyst::{
     window::ScreenDimensions,
     Error,
 };
-use rand::prelude::*;
-use std::collections::HashSet;
-
 use amethyst_nphysics::NPhysicsBackend;
 use amethyst_physics::{prelude::*, PhysicsBundle};
+use rand::random;
 use renderer::rendy::mesh::Indices;
+use std::path::PathBuf;
 
+mod camera_boom;
+mod camera_path;
+mod character_config;
 mod character_systems;
+mod chunk_octree;
+mod chunk_registry;
+mod chunk_scheduler;
+mod chunk_stats;
 mod chunk_system;
 mod components;
+mod config;
+mod density_benchmark;
+mod floating_origin;
+mod headless_bench;
+mod input;
+mod input_tape;
 mod marching_cubes;
 mod matrix_3d;
+mod quantized_density;
+mod spectator;
+mod states;
 mod terrain;
+mod terrain_collision;
+mod terrain_materials;
 mod visual_utils;
+mod workers;
 
-use terrain::Terrain;
-
-#[derive(Default)]
-struct Example;
-
-impl SimpleState for Example {
-    fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
-        // Add light
-        add_light_entity(
-            data.world,
-            Srgb::new(1.0, 1.0, 1.0),
-            Vector3::new(0.0, -1.0, 0.0),
-            1.0,
-        );
-
-        // Create terrain
-        let terrain = Terrain::new(
-            random(),
-            15,
-            1.0,
-            vec![0.3, 0.65, 0.05],
-            vec![0.05, 0.1, 10.0],
-        );
-        data.world.insert(terrain);
-        data.world.register::<components::Chunk>();
-
-        // Create the character + camera.
-        create_character_entity(data.world);
-    }
-}
+use character_config::{CharacterConfig, CharacterConfigReloadSystem};
+use chunk_stats::{ChunkStatsConfig, ChunkStatsSystem};
+use input::GameBindings;
+use input_tape::InputTapeSystem;
+use terrain::{Terrain, TerrainConfig};
+use terrain_collision::{CollisionConfig, CollisionDebugSystem, TerrainCollisionSystem};
+use terrain_materials::TerrainMaterialsReloadSystem;
+
+/// Size of the character's collision capsule.
+const CAPSULE_HALF_HEIGHT: f32 = 0.75;
+const CAPSULE_RADIUS: f32 = 0.5;
+/// Where the character is created, once the chunks around it have loaded.
+const SPAWN_POSITION: [f32; 3] = [10.0, 30.0, 10.0];
+/// Radius, in chunks, kept loaded around the character.
+const LOAD_RADIUS: u32 = 5;
 
 fn main() -> Result<(), Error> {
     amethyst::start_logger(Default::default());
@@ -76,31 +79,121 @@ fn main() -> Result<(), Error> {
 
     let assets_dir = app_root.join("assets");
     let display_config_path = app_root.join("config").join("display.ron");
+    let collision_config: CollisionConfig =
+        config::load_ron(&app_root.join("config").join("collision.ron"))?;
+    let chunk_stats_config: ChunkStatsConfig =
+        config::load_ron(&app_root.join("config").join("chunk_stats.ron"))?;
+    let mut terrain_config: TerrainConfig =
+        config::load_ron(&app_root.join("config").join("terrain.ron"))?;
+    let character_config_path = app_root.join("config").join("character.ron");
+    let character_config: CharacterConfig = config::load_ron(&character_config_path)?;
+
+    let args: Vec<String> = std::env::args().collect();
+    let flag_value = |flag: &str| {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|i| args.get(i + 1))
+            .map(PathBuf::from)
+    };
+
+    if args.iter().any(|arg| arg == "--bench-density") {
+        density_benchmark::run(&terrain_config.build(terrain_config.seed.unwrap_or(0).into()));
+        return Ok(());
+    }
+
+    // `--headless-bench [camera path]` streams along the camera path without a window.
+    if args.iter().any(|arg| arg == "--headless-bench") {
+        let seed = terrain_config.seed.unwrap_or(0);
+        // The path is optional, so another flag may follow instead.
+        let path = flag_value("--headless-bench")
+            .filter(|path| !path.to_string_lossy().starts_with("--"))
+            .unwrap_or_else(|| app_root.join("config").join("camera_path.ron"));
+        if !path.is_file() {
+            return Err(Error::from_string(format!("no camera path at {:?}", path)));
+        }
+        return headless_bench::run(&terrain_config.build(seed.into()), seed, &path);
+    }
+
+    // `--record <file>` saves the input played with, `--replay <file>` plays it back
+    // in the same world.
+    let (input_tape_system, deterministic) = if let Some(path) = flag_value("--replay") {
+        let tape = input_tape::Tape::load(&path)?;
+        terrain_config.seed = Some(tape.seed);
+        (InputTapeSystem::replay(tape), true)
+    } else if let Some(path) = flag_value("--record") {
+        let seed = terrain_config.seed.unwrap_or_else(random);
+        terrain_config.seed = Some(seed);
+        (InputTapeSystem::record(&path, seed)?, true)
+    } else {
+        (InputTapeSystem::live(), false)
+    };
 
     let game_data = GameDataBuilder::default()
         .with_bundle(
-            InputBundle::<StringBindings>::new()
-                .with_bindings_from_file(assets_dir.join("input_bindings.ron"))
-                .unwrap(),
+            InputBundle::<GameBindings>::new().with_bindings(input::load_bindings(
+                &assets_dir.join("input_bindings.ron"),
+                &app_root.join("config").join("input_bindings.ron"),
+            )?),
         )?
         .with(
-            character_systems::CameraMotionSystem::new(),
-            "camera_motion_system",
+            camera_boom::CameraBoomSystem::new(),
+            "camera_boom_system",
             &["input_system"],
         )
+        .with(
+            spectator::SpectatorSystem::new(
+                app_root.join("config").join("camera_path.ron"),
+                app_root.join("config").join("recorded_camera_path.ron"),
+            ),
+            "spectator_system",
+            &["input_system"],
+        )
+        .with_bundle(UiBundle::<GameBindings>::new())?
+        .with(ChunkStatsSystem::new(), "chunk_stats_system", &["input_system"])
+        .with(CollisionDebugSystem, "collision_debug_system", &[])
+        .with(
+            TerrainMaterialsReloadSystem,
+            "terrain_materials_reload_system",
+            &[],
+        )
+        .with(
+            CharacterConfigReloadSystem::new(character_config_path),
+            "character_config_reload_system",
+            &[],
+        )
         .with_bundle(TransformBundle::new())?
         .with_bundle(
             PhysicsBundle::<f32, NPhysicsBackend>::new()
                 .with_frames_per_seconds(60)
                 .with_max_sub_steps(8) // Safety
+                .with_pre_physics(
+                    floating_origin::FloatingOriginSystem,
+                    String::from("floating origin system"),
+                    vec![],
+                )
+                .with_pre_physics(
+                    input_tape_system,
+                    String::from("input tape system"),
+                    vec![String::from("floating origin system")],
+                )
+                .with_pre_physics(
+                    character_systems::CameraMotionSystem,
+                    String::from("camera motion system"),
+                    vec![String::from("input tape system")],
+                )
                 .with_pre_physics(
                     character_systems::CharacterMotionControllerSystem::new(),
                     String::from("character_motion_controller"),
-                    vec![],
+                    vec![String::from("camera motion system")],
                 ).with_pre_physics(
-                    chunk_system::ChunkSystem { chunk_posns: HashSet::new() },
+                    chunk_system::ChunkSystem::default(),
                     String::from("chunk system"),
-                    vec![],
+                    vec![String::from("floating origin system")],
+                ).with_pre_physics(
+                    TerrainCollisionSystem::new(&collision_config)
+                        .with_deterministic(deterministic),
+                    String::from("terrain collision system"),
+                    vec![String::from("chunk system")],
                 ),
         )?
         .with_bundle(
@@ -110,9 +203,16 @@ fn main() -> Result<(), Error> {
                         .unwrap()
                         .with_clear([0.7188, 0.2578, 0.0586, 1.0]),
                 )
-                .with_plugin(RenderShaded3D::default()),
+                .with_plugin(RenderShaded3D::default())
+                .with_plugin(RenderDebugLines::default())
+                .with_plugin(RenderUi::default()),
         )?;
-    let mut game = Application::build(assets_dir, Example::default())?.build(game_data)?;
+    let mut game = Application::build(assets_dir, states::LoadingState::default())?
+        .with_resource(collision_config)
+        .with_resource(chunk_stats_config)
+        .with_resource(terrain_config)
+        .with_resource(character_config)
+        .build(game_data)?;
     game.run();
     Ok(())
 }
@@ -136,8 +236,8 @@ fn create_character_entity(world: &mut World) {
     let character = {
         let shape = {
             let desc = ShapeDesc::Capsule {
-                half_height: 0.75,
-                radius: 0.5,
+                half_height: CAPSULE_HALF_HEIGHT,
+                radius: CAPSULE_RADIUS,
             };
             let physics_world = world.fetch::<PhysicsWorld<f32>>();
             physics_world.shape_server().create(&desc)
@@ -157,7 +257,7 @@ fn create_character_entity(world: &mut World) {
         };
 
         let mut transf = Transform::default();
-        transf.set_translation(Vector3::new(10.0, 30.0, 10.0));
+        transf.set_translation(Vector3::from(SPAWN_POSITION));
 
         world
             .create_entity()
@@ -165,6 +265,17 @@ fn create_character_entity(world: &mut World) {
             .with(shape)
             .with(rb)
             .with(components::CharacterBody)
+            .with(components::CharacterController {
+                max_slope_angle: 50.0,
+                step_height: 0.6,
+                snap_distance: 0.5,
+                feet_offset: CAPSULE_HALF_HEIGHT + CAPSULE_RADIUS,
+                radius: CAPSULE_RADIUS,
+            })
+            .with(components::ChunkLoader {
+                radius: LOAD_RADIUS,
+                priority: 0,
+            })
             .build()
     };
 
@@ -184,15 +295,23 @@ fn create_character_entity(world: &mut World) {
         let mut camera_transform = Transform::default();
         camera_transform.set_translation_xyz(0.0, 0.0, 0.0);
 
-        let (width, height) = {
+        let (camera, boom) = {
             let dim = world.read_resource::<ScreenDimensions>();
-            (dim.width(), dim.height())
+            let config = world.read_resource::<CharacterConfig>();
+            let boom = components::CameraBoom {
+                third_person: config.third_person,
+                length: config.boom_length,
+                distance: 0.0,
+                shoulder: 0.0,
+            };
+            (config.camera(dim.width(), dim.height()), boom)
         };
 
         world
             .create_entity()
             .with(camera_transform)
-            .with(Camera::standard_3d(width, height))
+            .with(camera)
+            .with(boom)
             .with(Parent {
                 entity: camera_boom_handle,
             })
@@ -78,10 +78,11 @@ impl DeserTriangulation {
     }
 }
 
-const CUTOFF: f32 = 0.0;
+/// Density at which the surface lies. Lower density is solid, higher is air.
+pub const CUTOFF: f32 = 0.0;
 
 fn get_cube_tris(
-    matrix: &Matrix3D,
+    matrix: &Matrix3D<f32>,
     vector: Vector3<usize>,
     triangulation: &Triangulation,
     interpolated: bool,
@@ -92,7 +93,7 @@ fn get_cube_tris(
     for i in 0..8 {
         let point = &CUBE_POINTS[i];
         let val =
-            matrix.get(vector + Vector3::new(point.x as usize, point.y as usize, point.z as usize));
+            matrix[vector + Vector3::new(point.x as usize, point.y as usize, point.z as usize)];
         vals[i] = val;
         if val < CUTOFF {
             id += 2usize.pow(i as u32);
@@ -145,29 +146,49 @@ fn correct(pts: Vec<Vector3<f32>>, scale: f32, displace: Vector3<usize>) -> Vec<
     return new;
 }
 
+/// Meshes the cubes of `matrix`, leaving out `apron` layers of samples on every
+/// side. Positions are relative to the first sample inside the apron. With an
+/// apron, normals follow the density gradient, which is then continuous across
+/// meshes of neighbouring volumes; without one, each triangle gets its face normal.
 pub fn get_mesh_data(
-    matrix: &Matrix3D,
+    matrix: &Matrix3D<f32>,
     scale: f32,
     triangulation_method: TriangulationMethod,
     interpolated: bool,
+    apron: usize,
 ) -> MeshData {
     let mut posns = vec![];
     let mut norms = vec![];
     let mut coords = vec![];
     let triangulation_method = TRIANGULATION.get(&triangulation_method).unwrap();
-    for z in 0..(matrix.z() - 1) {
-        for y in 0..(matrix.y() - 1) {
-            for x in 0..(matrix.x() - 1) {
+    let start = Vector3::repeat(apron);
+    for z in apron..(matrix.z().saturating_sub(apron + 1)) {
+        for y in apron..(matrix.y().saturating_sub(apron + 1)) {
+            for x in apron..(matrix.x().saturating_sub(apron + 1)) {
                 let vector = Vector3::new(x, y, z);
-                let pts = correct(
-                    get_cube_tris(matrix, vector, triangulation_method, interpolated),
-                    scale,
-                    vector,
-                );
+                let tris = get_cube_tris(matrix, vector, triangulation_method, interpolated);
+                let pts = correct(tris.clone(), scale, vector - start);
                 for pt in &pts {
                     posns.push(Position {
                         0: [pt.x, pt.y, pt.z],
                     });
+                    coords.push(TexCoord { 0: [0.0, 0.0] });
+                }
+                if apron > 0 {
+                    let cube = Vector3::new(x as f32, y as f32, z as f32);
+                    for pt in &tris {
+                        let gradient = matrix.gradient(cube + pt);
+                        // Density rises towards the air, so the gradient points out of the surface.
+                        let normal = if gradient.norm() > 0.0 {
+                            gradient.normalize()
+                        } else {
+                            Vector3::y()
+                        };
+                        norms.push(Normal {
+                            0: [normal.x, normal.y, normal.z],
+                        });
+                    }
+                    continue;
                 }
                 for i in 0..pts.len() / 3 {
                     let normal: Vector3<f32> =
@@ -176,7 +197,6 @@ pub fn get_mesh_data(
                         norms.push(Normal {
                             0: [normal.x, normal.y, normal.z],
                         });
-                        coords.push(TexCoord { 0: [0.0, 0.0] });
                     }
                 }
             }
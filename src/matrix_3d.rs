@@ -1,29 +1,205 @@
 use amethyst::core::math::Vector3;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    mem::size_of,
+    ops::{Index, IndexMut},
+};
 
-pub struct Matrix3D {
+/// Largest palette a `Matrix3D` compacts to; more distinct values are stored densely.
+const MAX_PALETTE: usize = 256;
+
+/// Every coordinate in a volume of size `dim`, in storage order (x fastest, then y, then z).
+pub fn coords(dim: Vector3<usize>) -> impl Iterator<Item = Vector3<usize>> {
+    return (0..dim.z).flat_map(move |z| {
+        (0..dim.y).flat_map(move |y| (0..dim.x).map(move |x| Vector3::new(x, y, z)))
+    });
+}
+
+/// Palette indices packed into words, `bits` per index. `bits` is a power of two,
+/// so an index never straddles two words.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PackedIndices {
+    bits: u32,
+    words: Vec<u64>,
+}
+
+impl PackedIndices {
+    fn new(bits: u32, len: usize) -> Self {
+        let per_word = (64 / bits) as usize;
+        PackedIndices {
+            bits,
+            words: vec![0; (len + per_word - 1) / per_word],
+        }
+    }
+
+    /// Fewest bits (1, 2, 4 or 8) that can index a palette of `len` values.
+    fn bits_for(len: usize) -> u32 {
+        let mut bits = 1;
+        while (1usize << bits) < len {
+            bits *= 2;
+        }
+        return bits;
+    }
+
+    fn get(&self, i: usize) -> usize {
+        let per_word = (64 / self.bits) as usize;
+        let shift = (i % per_word) as u32 * self.bits;
+        let mask = (1u64 << self.bits) - 1;
+        return ((self.words[i / per_word] >> shift) & mask) as usize;
+    }
+
+    fn set(&mut self, i: usize, index: usize) {
+        let per_word = (64 / self.bits) as usize;
+        let shift = (i % per_word) as u32 * self.bits;
+        let mask = ((1u64 << self.bits) - 1) << shift;
+        let word = &mut self.words[i / per_word];
+        *word = (*word & !mask) | ((index as u64) << shift);
+    }
+
+    fn repacked(&self, bits: u32, len: usize) -> Self {
+        let mut packed = PackedIndices::new(bits, len);
+        for i in 0..len {
+            packed.set(i, self.get(i));
+        }
+        return packed;
+    }
+}
+
+/// How a `Matrix3D` holds its values. Picked by `Matrix3D::compact`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Storage<T> {
+    /// Every element has the same value.
+    Uniform(T),
+    /// A few distinct values, referenced by bit-packed indices. Suits material grids.
+    Palette {
+        palette: Vec<T>,
+        indices: PackedIndices,
+    },
+    /// Runs of equal values in storage order: run `i` holds `values[i]` and ends
+    /// before offset `ends[i]`. Suits grids with long constant stretches.
+    Runs {
+        ends: Vec<u32>,
+        values: Vec<T>,
+    },
+    Dense(Vec<T>),
+}
+
+/// A 3D grid of values: densities, material IDs, light levels, edit masks...
+///
+/// `get` and `set` are bounds-checked. Indexing with `matrix[posn]` panics on
+/// out-of-range coordinates, for hot loops that already know they are in range.
+///
+/// Constant, low-variety or run-heavy grids can be `compact`ed into a single
+/// value, a palette or runs, losslessly. Reads work on any representation;
+/// mutable borrows expand the matrix back to dense storage, while `set` keeps
+/// uniform and palette matrices compact where it can.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "MatrixData<T>")]
+pub struct Matrix3D<T> {
     dim: Vector3<usize>,
-    elems: Vec<f32>,
+    storage: Storage<T>,
 }
 
-impl Matrix3D {
-    pub fn new(dim: Vector3<usize>) -> Self {
+/// Serialized form of a `Matrix3D`, checked on the way in.
+#[derive(Deserialize)]
+struct MatrixData<T> {
+    dim: Vector3<usize>,
+    storage: Storage<T>,
+}
+
+impl<T> TryFrom<MatrixData<T>> for Matrix3D<T> {
+    type Error = String;
+
+    fn try_from(data: MatrixData<T>) -> Result<Self, Self::Error> {
+        let len = data.dim.x * data.dim.y * data.dim.z;
+        match &data.storage {
+            Storage::Uniform(_) => {}
+            Storage::Palette { palette, indices } => {
+                if ![1, 2, 4, 8].contains(&indices.bits) {
+                    return Err(format!("palette indices can't be {} bits", indices.bits));
+                }
+                if indices.words.len() != PackedIndices::new(indices.bits, len).words.len() {
+                    return Err(format!("palette indices don't cover {} elements", len));
+                }
+                if (0..len).any(|i| indices.get(i) >= palette.len()) {
+                    return Err(String::from("palette index out of range"));
+                }
+            }
+            Storage::Runs { ends, values } => {
+                if ends.len() != values.len() {
+                    return Err(format!(
+                        "{} run ends for {} run values",
+                        ends.len(),
+                        values.len()
+                    ));
+                }
+                if ends.windows(2).any(|pair| pair[0] >= pair[1]) || ends.first() == Some(&0) {
+                    return Err(String::from("runs must be in order and not empty"));
+                }
+                if ends.last().map_or(0, |end| *end as usize) != len {
+                    return Err(format!("runs don't cover {} elements", len));
+                }
+            }
+            Storage::Dense(elems) => {
+                if elems.len() != len {
+                    return Err(format!(
+                        "matrix of size {}x{}x{} needs {} elements, got {}",
+                        data.dim.x,
+                        data.dim.y,
+                        data.dim.z,
+                        len,
+                        elems.len()
+                    ));
+                }
+            }
+        }
+        return Ok(Matrix3D {
+            dim: data.dim,
+            storage: data.storage,
+        });
+    }
+}
+
+impl<T> Matrix3D<T> {
+    /// Builds a densely stored matrix by calling `f` with every coordinate, in storage order.
+    pub fn from_fn(dim: Vector3<usize>, f: impl FnMut(Vector3<usize>) -> T) -> Self {
         Matrix3D {
             dim,
-            elems: vec![0.0; dim.x * dim.y * dim.z],
+            storage: Storage::Dense(coords(dim).map(f).collect()),
         }
     }
 
-    fn index(&self, vec: Vector3<usize>) -> usize {
+    fn offset(&self, vec: Vector3<usize>) -> usize {
         return vec.z * self.dim.x * self.dim.y + vec.y * self.dim.x + vec.x;
     }
 
-    pub fn get(&self, vec: Vector3<usize>) -> f32 {
-        return self.elems[self.index(vec)];
+    /// The element at storage offset `i`, which must be in range.
+    fn at(&self, i: usize) -> &T {
+        match &self.storage {
+            Storage::Uniform(val) => return val,
+            Storage::Palette { palette, indices } => return &palette[indices.get(i)],
+            Storage::Runs { ends, values } => {
+                // The run containing `i` is the first one ending after it.
+                match ends.binary_search(&(i as u32)) {
+                    Ok(run) => return &values[run + 1],
+                    Err(run) => return &values[run],
+                }
+            }
+            Storage::Dense(elems) => return &elems[i],
+        }
     }
 
-    pub fn set(&mut self, vec: Vector3<usize>, val: f32) {
-        let index = self.index(vec);
-        self.elems[index] = val;
+    pub fn contains(&self, vec: Vector3<usize>) -> bool {
+        return vec.x < self.dim.x && vec.y < self.dim.y && vec.z < self.dim.z;
+    }
+
+    /// Returns `None` if `vec` is out of range.
+    pub fn get(&self, vec: Vector3<usize>) -> Option<&T> {
+        if !self.contains(vec) {
+            return None;
+        }
+        return Some(self.at(self.offset(vec)));
     }
 
     pub fn x(&self) -> usize {
@@ -37,4 +213,725 @@ impl Matrix3D {
     pub fn z(&self) -> usize {
         return self.dim.z;
     }
+
+    pub fn len(&self) -> usize {
+        return self.dim.x * self.dim.y * self.dim.z;
+    }
+
+    /// Approximate bytes used by the elements in their current representation.
+    pub fn memory(&self) -> usize {
+        match &self.storage {
+            Storage::Uniform(_) => return size_of::<T>(),
+            Storage::Palette { palette, indices } => {
+                return palette.len() * size_of::<T>() + indices.words.len() * size_of::<u64>();
+            }
+            Storage::Runs { ends, values } => {
+                return ends.len() * size_of::<u32>() + values.len() * size_of::<T>();
+            }
+            Storage::Dense(elems) => return elems.len() * size_of::<T>(),
+        }
+    }
+
+    /// The values in storage order, matching `coords(self.dim())`.
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        return (0..self.len()).map(move |i| self.at(i));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Vector3<usize>, &T)> {
+        return coords(self.dim).zip(self.values());
+    }
+
+    /// Applies `f` to every element. Compact matrices only map their stored
+    /// values and keep their representation.
+    pub fn map<U>(&self, mut f: impl FnMut(&T) -> U) -> Matrix3D<U> {
+        let storage = match &self.storage {
+            Storage::Uniform(val) => Storage::Uniform(f(val)),
+            Storage::Palette { palette, indices } => Storage::Palette {
+                palette: palette.iter().map(f).collect(),
+                indices: indices.clone(),
+            },
+            Storage::Runs { ends, values } => Storage::Runs {
+                ends: ends.clone(),
+                values: values.iter().map(f).collect(),
+            },
+            Storage::Dense(elems) => Storage::Dense(elems.iter().map(f).collect()),
+        };
+        Matrix3D {
+            dim: self.dim,
+            storage,
+        }
+    }
+
+    /// A read-only window onto the `dim` sized sub-volume starting at `min`.
+    /// Returns `None` if it doesn't fit inside the matrix.
+    pub fn view(&self, min: Vector3<usize>, dim: Vector3<usize>) -> Option<Matrix3DView<'_, T>> {
+        let end = min + dim;
+        if end.x > self.dim.x || end.y > self.dim.y || end.z > self.dim.z {
+            return None;
+        }
+        return Some(Matrix3DView {
+            matrix: self,
+            min,
+            dim,
+        });
+    }
+}
+
+impl<T: Clone> Matrix3D<T> {
+    /// Expands the matrix to one stored value per element.
+    fn make_dense(&mut self) -> &mut Vec<T> {
+        let elems = match &self.storage {
+            Storage::Dense(_) => None,
+            _ => Some(self.values().cloned().collect()),
+        };
+        if let Some(elems) = elems {
+            self.storage = Storage::Dense(elems);
+        }
+        match &mut self.storage {
+            Storage::Dense(elems) => return elems,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Mutable access expands the matrix to dense storage.
+    pub fn get_mut(&mut self, vec: Vector3<usize>) -> Option<&mut T> {
+        if !self.contains(vec) {
+            return None;
+        }
+        let offset = self.offset(vec);
+        return Some(&mut self.make_dense()[offset]);
+    }
+}
+
+impl<T: Clone + PartialEq> Matrix3D<T> {
+    /// Returns false, leaving the matrix untouched, if `vec` is out of range.
+    /// Uniform and palette matrices stay compact while the palette has room.
+    pub fn set(&mut self, vec: Vector3<usize>, val: T) -> bool {
+        if !self.contains(vec) {
+            return false;
+        }
+        let offset = self.offset(vec);
+        let len = self.len();
+        let replacement = match &mut self.storage {
+            Storage::Uniform(current) => {
+                if *current == val {
+                    return true;
+                }
+                let mut indices = PackedIndices::new(1, len);
+                indices.set(offset, 1);
+                Storage::Palette {
+                    palette: vec![current.clone(), val],
+                    indices,
+                }
+            }
+            Storage::Palette { palette, indices } => {
+                if let Some(index) = palette.iter().position(|p| *p == val) {
+                    indices.set(offset, index);
+                    return true;
+                }
+                if palette.len() == MAX_PALETTE {
+                    let mut elems: Vec<T> = (0..len)
+                        .map(|i| palette[indices.get(i)].clone())
+                        .collect();
+                    elems[offset] = val;
+                    Storage::Dense(elems)
+                } else {
+                    palette.push(val);
+                    let bits = PackedIndices::bits_for(palette.len());
+                    if bits != indices.bits {
+                        *indices = indices.repacked(bits, len);
+                    }
+                    indices.set(offset, palette.len() - 1);
+                    return true;
+                }
+            }
+            Storage::Runs { .. } => {
+                self.make_dense()[offset] = val;
+                return true;
+            }
+            Storage::Dense(elems) => {
+                elems[offset] = val;
+                return true;
+            }
+        };
+        self.storage = replacement;
+        return true;
+    }
+
+    /// Switches to the smallest representation holding the same values: a single
+    /// value, a palette of at most `MAX_PALETTE` values, runs, or dense storage.
+    pub fn compact(&mut self) {
+        let len = self.len();
+        // Distinct values, until there are too many for a palette, and the number of runs.
+        let mut palette: Vec<T> = vec![];
+        let mut palette_full = false;
+        let mut runs = 0;
+        let mut previous = None;
+        for val in self.values() {
+            if previous != Some(val) {
+                runs += 1;
+                previous = Some(val);
+            }
+            if !palette_full && !palette.contains(val) {
+                if palette.len() == MAX_PALETTE {
+                    palette_full = true;
+                    palette.clear();
+                } else {
+                    palette.push(val.clone());
+                }
+            }
+        }
+        if len == 0 {
+            self.make_dense();
+            return;
+        }
+        if palette.len() == 1 {
+            self.storage = Storage::Uniform(palette.pop().unwrap());
+            return;
+        }
+
+        let dense_size = len * size_of::<T>();
+        let runs_size = runs * (size_of::<u32>() + size_of::<T>());
+        let palette_size = if palette_full {
+            std::usize::MAX
+        } else {
+            let bits = PackedIndices::bits_for(palette.len());
+            palette.len() * size_of::<T>()
+                + PackedIndices::new(bits, len).words.len() * size_of::<u64>()
+        };
+        if palette_size < dense_size && palette_size <= runs_size {
+            let mut indices = PackedIndices::new(PackedIndices::bits_for(palette.len()), len);
+            for (i, val) in self.values().enumerate() {
+                indices.set(i, palette.iter().position(|p| p == val).unwrap());
+            }
+            self.storage = Storage::Palette { palette, indices };
+        } else if runs_size < dense_size {
+            let mut ends: Vec<u32> = Vec::with_capacity(runs);
+            let mut values: Vec<T> = Vec::with_capacity(runs);
+            for (i, val) in self.values().enumerate() {
+                if values.last() == Some(val) {
+                    *ends.last_mut().unwrap() = i as u32 + 1;
+                } else {
+                    ends.push(i as u32 + 1);
+                    values.push(val.clone());
+                }
+            }
+            self.storage = Storage::Runs { ends, values };
+        } else {
+            self.make_dense();
+        }
+    }
+}
+
+/// The two grid points either side of `posn` on an axis of `len` points, and how
+/// far along between them `posn` lies. Positions outside the axis are clamped to it.
+fn cell(posn: f32, len: usize) -> (usize, usize, f32) {
+    let last = len.saturating_sub(1);
+    let posn = posn.max(0.0).min(last as f32);
+    let low = (posn.floor() as usize).min(last);
+    let high = (low + 1).min(last);
+    return (low, high, posn - low as f32);
+}
+
+impl Matrix3D<f32> {
+    /// Trilinearly interpolated value at `posn`, in grid units. Positions outside
+    /// the matrix are clamped to its border. Panics on an empty matrix.
+    pub fn sample(&self, posn: Vector3<f32>) -> f32 {
+        let (x0, x1, tx) = cell(posn.x, self.dim.x);
+        let (y0, y1, ty) = cell(posn.y, self.dim.y);
+        let (z0, z1, tz) = cell(posn.z, self.dim.z);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let c00 = lerp(self[Vector3::new(x0, y0, z0)], self[Vector3::new(x1, y0, z0)], tx);
+        let c10 = lerp(self[Vector3::new(x0, y1, z0)], self[Vector3::new(x1, y1, z0)], tx);
+        let c01 = lerp(self[Vector3::new(x0, y0, z1)], self[Vector3::new(x1, y0, z1)], tx);
+        let c11 = lerp(self[Vector3::new(x0, y1, z1)], self[Vector3::new(x1, y1, z1)], tx);
+        return lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz);
+    }
+
+    /// Rate of change of `sample` at `posn`, per grid unit, from central differences
+    /// one grid unit either side. At the border the differences become one-sided,
+    /// and along an axis with a single point the gradient is zero.
+    pub fn gradient(&self, posn: Vector3<f32>) -> Vector3<f32> {
+        let mut gradient = Vector3::zeros();
+        for axis in 0..3 {
+            let last = self.dim[axis].saturating_sub(1) as f32;
+            let center = posn[axis].max(0.0).min(last);
+            let low = (center - 1.0).max(0.0);
+            let high = (center + 1.0).min(last);
+            if high > low {
+                let mut low_posn = posn;
+                let mut high_posn = posn;
+                low_posn[axis] = low;
+                high_posn[axis] = high;
+                gradient[axis] = (self.sample(high_posn) - self.sample(low_posn)) / (high - low);
+            }
+        }
+        return gradient;
+    }
+
+    /// Resamples the matrix to `dim` points per axis, keeping its corners on the
+    /// corners of the original. Downsampling picks interpolated points rather
+    /// than averaging, so detail smaller than the new spacing is lost.
+    pub fn resample(&self, dim: Vector3<usize>) -> Matrix3D<f32> {
+        let scale = |old: usize, new: usize| {
+            if new > 1 {
+                return old.saturating_sub(1) as f32 / (new - 1) as f32;
+            }
+            return 0.0;
+        };
+        let scale = Vector3::new(
+            scale(self.dim.x, dim.x),
+            scale(self.dim.y, dim.y),
+            scale(self.dim.z, dim.z),
+        );
+        return Matrix3D::from_fn(dim, |posn| {
+            self.sample(Vector3::new(
+                posn.x as f32 * scale.x,
+                posn.y as f32 * scale.y,
+                posn.z as f32 * scale.z,
+            ))
+        });
+    }
+}
+
+impl<T: PartialEq> PartialEq for Matrix3D<T> {
+    /// Matrices are equal if they hold the same values, however they are stored.
+    fn eq(&self, other: &Self) -> bool {
+        return self.dim == other.dim && self.values().eq(other.values());
+    }
+}
+
+impl<T> Index<Vector3<usize>> for Matrix3D<T> {
+    type Output = T;
+
+    fn index(&self, vec: Vector3<usize>) -> &T {
+        match self.get(vec) {
+            Some(elem) => return elem,
+            None => panic!("{:?} is outside of a matrix of size {:?}", vec, self.dim),
+        }
+    }
+}
+
+impl<T: Clone> IndexMut<Vector3<usize>> for Matrix3D<T> {
+    fn index_mut(&mut self, vec: Vector3<usize>) -> &mut T {
+        let dim = self.dim;
+        match self.get_mut(vec) {
+            Some(elem) => return elem,
+            None => panic!("{:?} is outside of a matrix of size {:?}", vec, dim),
+        }
+    }
+}
+
+/// A sub-volume of a `Matrix3D`, addressed from its own minimum corner.
+#[derive(Debug)]
+pub struct Matrix3DView<'a, T> {
+    matrix: &'a Matrix3D<T>,
+    min: Vector3<usize>,
+    dim: Vector3<usize>,
+}
+
+impl<'a, T> Matrix3DView<'a, T> {
+    /// Copies the viewed elements into a matrix of their own.
+    pub fn to_matrix(&self) -> Matrix3D<T>
+    where
+        T: Clone,
+    {
+        return Matrix3D::from_fn(self.dim, |vec| self.matrix[self.min + vec].clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind<T>(matrix: &Matrix3D<T>) -> &'static str {
+        match matrix.storage {
+            Storage::Uniform(_) => return "uniform",
+            Storage::Palette { .. } => return "palette",
+            Storage::Runs { .. } => return "runs",
+            Storage::Dense(_) => return "dense",
+        }
+    }
+
+    fn palette_bits<T>(matrix: &Matrix3D<T>) -> Option<u32> {
+        match &matrix.storage {
+            Storage::Palette { indices, .. } => return Some(indices.bits),
+            _ => return None,
+        }
+    }
+
+    /// Coordinate of storage offset `i` in a matrix of size `dim`.
+    fn coord(dim: Vector3<usize>, i: usize) -> Vector3<usize> {
+        return coords(dim).nth(i).unwrap();
+    }
+
+    /// A matrix holding `val` everywhere, stored as a single value.
+    fn uniform<T: Clone + PartialEq>(dim: Vector3<usize>, val: T) -> Matrix3D<T> {
+        let mut matrix = Matrix3D::from_fn(dim, |_| val.clone());
+        matrix.compact();
+        return matrix;
+    }
+
+    /// One matrix of each storage kind, the same size, holding small numbers.
+    fn every_storage(dim: Vector3<usize>) -> Vec<Matrix3D<u16>> {
+        let mut matrices = vec![
+            Matrix3D::from_fn(dim, |_| 3),
+            Matrix3D::from_fn(dim, |p| (p.x % 3) as u16),
+            Matrix3D::from_fn(dim, |p| (p.z * 1000) as u16),
+            Matrix3D::from_fn(dim, |p| (p.x * 100 + p.y * 10 + p.z) as u16),
+        ];
+        for matrix in &mut matrices {
+            matrix.compact();
+        }
+        return matrices;
+    }
+
+    #[test]
+    fn get_returns_none_out_of_bounds() {
+        let dim = Vector3::new(6, 5, 4);
+        for matrix in every_storage(dim) {
+            assert!(matrix.get(Vector3::new(5, 4, 3)).is_some());
+            assert_eq!(matrix.get(Vector3::new(6, 0, 0)), None);
+            assert_eq!(matrix.get(Vector3::new(0, 5, 0)), None);
+            assert_eq!(matrix.get(Vector3::new(0, 0, 4)), None);
+            assert_eq!(matrix.get(Vector3::new(7, 7, 7)), None);
+        }
+        // Coordinates that would wrap onto another row are out of bounds, not aliased.
+        let matrix = Matrix3D::from_fn(dim, |p| p);
+        assert_eq!(matrix.get(Vector3::new(6, 1, 0)), None);
+    }
+
+    #[test]
+    fn iter_visits_every_coordinate_in_storage_order() {
+        let dim = Vector3::new(3, 2, 2);
+        let matrix = Matrix3D::from_fn(dim, |p| p);
+        assert!(matrix.iter().all(|(posn, val)| posn == *val));
+        let visited: Vec<Vector3<usize>> = matrix.iter().map(|(posn, _)| posn).collect();
+        assert_eq!(visited.len(), 12);
+        assert_eq!(visited[0], Vector3::new(0, 0, 0));
+        assert_eq!(visited[1], Vector3::new(1, 0, 0));
+        assert_eq!(visited[3], Vector3::new(0, 1, 0));
+        assert_eq!(visited[6], Vector3::new(0, 0, 1));
+        assert_eq!(visited[11], Vector3::new(2, 1, 1));
+        for matrix in every_storage(dim) {
+            assert!(matrix.iter().all(|(posn, val)| matrix[posn] == *val));
+            assert!(matrix.iter().map(|(_, val)| val).eq(matrix.values()));
+        }
+    }
+
+    #[test]
+    fn map_keeps_positions_and_storage() {
+        for matrix in every_storage(Vector3::new(6, 5, 4)) {
+            let mapped = matrix.map(|val| *val as u32 * 2 + 1);
+            assert_eq!(kind(&mapped), kind(&matrix));
+            assert!(mapped.iter().all(|(posn, val)| *val == matrix[posn] as u32 * 2 + 1));
+        }
+    }
+
+    #[test]
+    fn view_copies_a_sub_volume() {
+        let dim = Vector3::new(6, 5, 4);
+        let min = Vector3::new(1, 2, 0);
+        let size = Vector3::new(4, 3, 2);
+        for matrix in every_storage(dim) {
+            let view = matrix.view(min, size).unwrap().to_matrix();
+            assert_eq!(view, Matrix3D::from_fn(size, |p| matrix[min + p]));
+            assert_eq!(matrix.view(Vector3::zeros(), dim).unwrap().to_matrix(), matrix);
+        }
+    }
+
+    #[test]
+    fn view_must_fit_inside_the_matrix() {
+        let matrix = Matrix3D::from_fn(Vector3::new(6, 5, 4), |p| p);
+        assert!(matrix.view(Vector3::new(2, 2, 2), Vector3::new(4, 3, 2)).is_some());
+        assert!(matrix.view(Vector3::new(3, 2, 2), Vector3::new(4, 3, 2)).is_none());
+        assert!(matrix.view(Vector3::new(0, 0, 0), Vector3::new(6, 5, 5)).is_none());
+        assert!(matrix.view(Vector3::new(0, 5, 0), Vector3::new(1, 1, 1)).is_none());
+    }
+
+    #[test]
+    fn set_promotes_uniform_to_palette_to_dense() {
+        let dim = Vector3::new(8, 8, 8);
+        let mut matrix = uniform(dim, 0u16);
+        let mut expected = vec![0u16; matrix.len()];
+        assert_eq!(kind(&matrix), "uniform");
+
+        // Setting the value already there keeps it uniform.
+        assert!(matrix.set(coord(dim, 3), 0));
+        assert_eq!(kind(&matrix), "uniform");
+
+        for val in 1..(MAX_PALETTE as u16) {
+            let i = val as usize * 2;
+            assert!(matrix.set(coord(dim, i), val));
+            expected[i] = val;
+            assert_eq!(kind(&matrix), "palette");
+        }
+        assert!(matrix.values().cloned().eq(expected.iter().cloned()));
+
+        // One more distinct value than the palette holds.
+        let i = matrix.len() - 1;
+        assert!(matrix.set(coord(dim, i), 1000));
+        expected[i] = 1000;
+        assert_eq!(kind(&matrix), "dense");
+        assert!(matrix.values().cloned().eq(expected.iter().cloned()));
+    }
+
+    #[test]
+    fn palette_indices_repack_as_the_palette_grows() {
+        let dim = Vector3::new(5, 5, 5);
+        let mut matrix = uniform(dim, 0u8);
+        let mut expected = vec![0u8; matrix.len()];
+        // Palette length at which each bit width is first needed.
+        let widths = [(2, 1), (3, 2), (5, 4), (17, 8)];
+        for val in 1..17u8 {
+            // Spread over several words, so indices move between words when repacked.
+            let i = val as usize * 7;
+            matrix.set(coord(dim, i), val);
+            expected[i] = val;
+            let palette_len = val as usize + 1;
+            let bits = widths
+                .iter()
+                .rev()
+                .find(|(len, _)| palette_len >= *len)
+                .map(|(_, bits)| *bits);
+            assert_eq!(palette_bits(&matrix), bits, "palette of {}", palette_len);
+            assert!(matrix.values().cloned().eq(expected.iter().cloned()));
+        }
+    }
+
+    #[test]
+    fn set_overwrites_within_the_palette() {
+        let dim = Vector3::new(4, 4, 4);
+        let mut matrix = uniform(dim, 0u8);
+        matrix.set(coord(dim, 10), 1);
+        matrix.set(coord(dim, 10), 0);
+        matrix.set(coord(dim, 11), 1);
+        assert_eq!(kind(&matrix), "palette");
+        assert_eq!(matrix[coord(dim, 10)], 0);
+        assert_eq!(matrix[coord(dim, 11)], 1);
+        assert!(!matrix.set(Vector3::new(4, 0, 0), 1));
+    }
+
+    #[test]
+    fn compact_picks_the_smallest_storage() {
+        let dim = Vector3::new(16, 16, 16);
+        let cases: Vec<(&str, Matrix3D<u32>)> = vec![
+            ("uniform", Matrix3D::from_fn(dim, |_| 7)),
+            ("palette", Matrix3D::from_fn(dim, |p| ((p.x * 31 + p.y * 7 + p.z) % 5) as u32)),
+            ("runs", Matrix3D::from_fn(dim, |p| (p.z * 1000 + p.y / 8) as u32)),
+            ("dense", Matrix3D::from_fn(dim, |p| (p.x * 7919 + p.y * 31 + p.z) as u32)),
+        ];
+        for (expected, original) in cases {
+            let mut matrix = original.clone();
+            matrix.compact();
+            assert_eq!(kind(&matrix), expected);
+            assert_eq!(matrix, original);
+            assert!(matrix.iter().all(|(posn, val)| original.get(posn) == Some(val)));
+            assert!(matrix.memory() <= original.memory());
+        }
+    }
+
+    #[test]
+    fn set_on_runs_expands_to_dense() {
+        let dim = Vector3::new(16, 16, 16);
+        let mut matrix = Matrix3D::from_fn(dim, |p| (p.z * 1000 + p.y / 8) as u32);
+        matrix.compact();
+        assert_eq!(kind(&matrix), "runs");
+        let mut expected: Vec<u32> = matrix.values().cloned().collect();
+        matrix.set(coord(dim, 5), 1);
+        expected[5] = 1;
+        assert_eq!(kind(&matrix), "dense");
+        assert!(matrix.values().cloned().eq(expected.into_iter()));
+    }
+
+    #[test]
+    fn serde_round_trips_every_storage() {
+        for original in every_storage(Vector3::new(6, 5, 4)) {
+            let text = ron::ser::to_string(&original).unwrap();
+            let loaded: Matrix3D<u16> = ron::de::from_str(&text).unwrap();
+            assert_eq!(kind(&loaded), kind(&original));
+            assert_eq!(loaded, original);
+        }
+    }
+
+    #[test]
+    fn deserialize_checks_the_size_of_dense_storage() {
+        let dim = Vector3::new(3, 2, 1);
+        let written = |elems: Vec<u16>| {
+            let text = ron::ser::to_string(&Matrix3D {
+                dim,
+                storage: Storage::Dense(elems),
+            })
+            .unwrap();
+            return ron::de::from_str::<Matrix3D<u16>>(&text);
+        };
+        let loaded = written(vec![0, 1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(loaded[Vector3::new(1, 1, 0)], 4);
+        for len in &[0, 5, 7] {
+            let error = written(vec![0; *len]).unwrap_err().to_string();
+            assert!(error.contains("needs 6 elements"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn deserialize_rejects_malformed_storage() {
+        let dim = Vector3::new(2, 2, 2);
+        let mut wide_indices = PackedIndices::new(1, 8);
+        wide_indices.set(3, 1);
+        let malformed = vec![
+            Storage::Dense(vec![0u16; 7]),
+            Storage::Palette {
+                palette: vec![0, 1],
+                indices: PackedIndices {
+                    bits: 3,
+                    words: vec![0],
+                },
+            },
+            Storage::Palette {
+                palette: vec![0, 1],
+                indices: PackedIndices {
+                    bits: 1,
+                    words: vec![],
+                },
+            },
+            Storage::Palette {
+                palette: vec![0],
+                indices: wide_indices,
+            },
+            Storage::Runs {
+                ends: vec![4, 8],
+                values: vec![0],
+            },
+            Storage::Runs {
+                ends: vec![5, 4, 8],
+                values: vec![0, 1, 2],
+            },
+            Storage::Runs {
+                ends: vec![0, 8],
+                values: vec![0, 1],
+            },
+            Storage::Runs {
+                ends: vec![4, 7],
+                values: vec![0, 1],
+            },
+        ];
+        for storage in malformed {
+            let text = ron::ser::to_string(&Matrix3D { dim, storage }).unwrap();
+            assert!(ron::de::from_str::<Matrix3D<u16>>(&text).is_err(), "{}", text);
+        }
+    }
+
+    /// Points per axis of the sphere field, with the sphere centered in it.
+    const SPHERE_POINTS: usize = 33;
+    const SPHERE_RADIUS: f32 = 10.0;
+
+    fn sphere_center() -> Vector3<f32> {
+        return Vector3::repeat((SPHERE_POINTS - 1) as f32 / 2.0);
+    }
+
+    fn sphere_distance(posn: Vector3<f32>) -> f32 {
+        return (posn - sphere_center()).norm() - SPHERE_RADIUS;
+    }
+
+    fn sphere_field() -> Matrix3D<f32> {
+        return Matrix3D::from_fn(Vector3::repeat(SPHERE_POINTS), |posn| {
+            sphere_distance(Vector3::new(posn.x as f32, posn.y as f32, posn.z as f32))
+        });
+    }
+
+    #[test]
+    fn sample_matches_the_sphere_at_grid_points() {
+        let field = sphere_field();
+        for posn in &[
+            Vector3::new(16.0, 16.0, 16.0),
+            Vector3::new(26.0, 16.0, 16.0),
+            Vector3::new(9.0, 21.0, 12.0),
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(32.0, 32.0, 32.0),
+        ] {
+            assert!((field.sample(*posn) - sphere_distance(*posn)).abs() < 1e-4, "{}", posn);
+        }
+    }
+
+    #[test]
+    fn sample_interpolates_the_sphere_between_grid_points() {
+        let field = sphere_field();
+        // Inside cells and on cell faces and edges, near the surface.
+        for posn in &[
+            Vector3::new(26.5, 16.0, 16.0),
+            Vector3::new(25.5, 16.5, 16.0),
+            Vector3::new(23.25, 22.5, 16.75),
+            Vector3::new(9.5, 9.5, 12.5),
+            Vector3::new(16.0, 5.3, 16.9),
+            Vector3::new(20.5, 12.0, 24.5),
+        ] {
+            assert!((field.sample(*posn) - sphere_distance(*posn)).abs() < 0.05, "{}", posn);
+        }
+    }
+
+    #[test]
+    fn sample_clamps_out_of_range_positions() {
+        let field = sphere_field();
+        let clamped = [
+            (Vector3::new(-5.0, 16.0, 16.0), Vector3::new(0, 16, 16)),
+            (Vector3::new(40.0, 16.0, 16.0), Vector3::new(32, 16, 16)),
+            (Vector3::new(16.0, -0.5, 100.0), Vector3::new(16, 0, 32)),
+            (Vector3::new(-1.0, -1.0, -1.0), Vector3::new(0, 0, 0)),
+        ];
+        for (posn, border) in clamped.iter() {
+            assert_eq!(field.sample(*posn), field[*border], "{}", posn);
+        }
+    }
+
+    #[test]
+    fn gradient_points_away_from_the_sphere_center() {
+        let field = sphere_field();
+        for posn in &[
+            Vector3::new(26.0, 16.0, 16.0),
+            Vector3::new(6.5, 16.0, 16.0),
+            Vector3::new(23.25, 22.5, 16.75),
+            Vector3::new(9.5, 9.5, 12.5),
+            Vector3::new(16.0, 16.0, 29.0),
+        ] {
+            let gradient = field.gradient(*posn);
+            let radial = (posn - sphere_center()).normalize();
+            assert!((gradient.norm() - 1.0).abs() < 0.05, "{}: {}", posn, gradient);
+            let angle = gradient.normalize().dot(&radial).min(1.0).acos();
+            assert!(angle.to_degrees() < 2.0, "{}: {}", posn, gradient);
+        }
+    }
+
+    #[test]
+    fn gradient_is_one_sided_at_the_border() {
+        let field = sphere_field();
+        // On the x border the differences are taken from the border inwards,
+        // where the distance falls by one per grid unit.
+        for posn in &[Vector3::new(0.0, 16.0, 16.0), Vector3::new(-3.0, 16.0, 16.0)] {
+            let gradient = field.gradient(*posn);
+            assert!((gradient - Vector3::new(-1.0, 0.0, 0.0)).norm() < 1e-4, "{}", gradient);
+        }
+        let gradient = field.gradient(Vector3::new(16.0, 40.0, 16.0));
+        assert!((gradient - Vector3::new(0.0, 1.0, 0.0)).norm() < 1e-4, "{}", gradient);
+    }
+
+    #[test]
+    fn resample_keeps_the_sphere() {
+        let field = sphere_field();
+        let half_dim = Vector3::repeat((SPHERE_POINTS - 1) / 2 + 1);
+        let half = field.resample(half_dim);
+        // Every other point of the original lands on a point of the halved field.
+        for (posn, val) in half.iter() {
+            assert!((val - field[posn * 2]).abs() < 1e-4, "{}", posn);
+        }
+        // Restoring the resolution interpolates with twice the spacing. Near the
+        // surface the curvature is at most 1/7, so the error stays within
+        // 3 * 2^2 / 8 / 7; around the center, where the field is a cone, it is larger.
+        let restored = half.resample(Vector3::repeat(SPHERE_POINTS));
+        for (posn, val) in restored.iter() {
+            let exact = field[posn];
+            let bound = if exact.abs() < 3.0 { 0.22 } else { 1.0 };
+            assert!((val - exact).abs() < bound, "{:?}: {} vs {}", posn, val, exact);
+        }
+        assert_eq!(field.resample(Vector3::repeat(SPHERE_POINTS)), field);
+    }
 }
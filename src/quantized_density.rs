@@ -0,0 +1,252 @@
+use crate::matrix_3d::Matrix3D;
+use serde::{Deserialize, Serialize};
+use std::mem::size_of;
+
+/// Float rounding allowed for on top of half a level, relative to the largest
+/// magnitude among the samples: encoding and decoding each round a few times.
+const ROUNDING: f32 = 8.0 * std::f32::EPSILON;
+
+/// Quantized levels, compacted by the `Matrix3D` into runs, a palette or a
+/// single value wherever that is smaller.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Levels {
+    Bits8(Matrix3D<u8>),
+    Bits16(Matrix3D<u16>),
+    /// The samples themselves, compacted, for when no quantization is close enough.
+    Exact(Matrix3D<f32>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Precision {
+    Bits8,
+    Bits16,
+    Exact,
+}
+
+impl Precision {
+    /// Number of steps between the lowest and highest quantized value,
+    /// or `None` if the samples aren't quantized.
+    fn steps(self) -> Option<u32> {
+        match self {
+            Precision::Bits8 => return Some(std::u8::MAX as u32),
+            Precision::Bits16 => return Some(std::u16::MAX as u32),
+            Precision::Exact => return None,
+        }
+    }
+}
+
+/// Density samples squeezed for storage: each sample is rounded to one of
+/// 2^8 or 2^16 evenly spaced levels between the smallest and largest density,
+/// and the levels are compacted, so runs of equal levels collapse.
+///
+/// Decoded densities are within `max_error` of the originals (half a level,
+/// plus float rounding), so the surface moves by at most that much.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuantizedDensity {
+    min: f32,
+    step: f32,
+    max_error: f32,
+    levels: Levels,
+}
+
+/// Smallest and largest sample.
+fn range(matrix: &Matrix3D<f32>) -> (f32, f32) {
+    let min = matrix.values().cloned().fold(std::f32::INFINITY, f32::min);
+    let max = matrix.values().cloned().fold(std::f32::NEG_INFINITY, f32::max);
+    return (min, max);
+}
+
+/// Distance between `steps + 1` levels spanning `min` to `max`.
+fn step_size(min: f32, max: f32, steps: u32) -> f32 {
+    if max > min {
+        return (max - min) / steps as f32;
+    }
+    return 0.0;
+}
+
+/// Largest difference between a decoded sample and the original.
+fn quantization_error(min: f32, max: f32, step: f32) -> f32 {
+    return step / 2.0 + ROUNDING * min.abs().max(max.abs());
+}
+
+impl QuantizedDensity {
+    pub fn new(matrix: &Matrix3D<f32>, precision: Precision) -> Self {
+        let (min, max) = range(matrix);
+        return QuantizedDensity::quantize(matrix, precision, min, max);
+    }
+
+    /// The smallest encoding that keeps every sample within `max_error`,
+    /// falling back to the exact samples if even 16 bits can't.
+    pub fn within(matrix: &Matrix3D<f32>, max_error: f32) -> Self {
+        let (min, max) = range(matrix);
+        for precision in &[Precision::Bits8, Precision::Bits16] {
+            let step = step_size(min, max, precision.steps().unwrap());
+            if quantization_error(min, max, step) <= max_error {
+                return QuantizedDensity::quantize(matrix, *precision, min, max);
+            }
+        }
+        return QuantizedDensity::quantize(matrix, Precision::Exact, min, max);
+    }
+
+    fn quantize(matrix: &Matrix3D<f32>, precision: Precision, min: f32, max: f32) -> Self {
+        let steps = match precision.steps() {
+            Some(steps) => steps,
+            None => {
+                let mut samples = matrix.clone();
+                samples.compact();
+                return QuantizedDensity {
+                    min: 0.0,
+                    step: 0.0,
+                    max_error: 0.0,
+                    levels: Levels::Exact(samples),
+                };
+            }
+        };
+        let step = step_size(min, max, steps);
+        let level = |val: &f32| {
+            if step == 0.0 {
+                return 0;
+            }
+            return ((val - min) / step).round() as u32;
+        };
+        let levels = match precision {
+            Precision::Bits8 => {
+                let mut levels = matrix.map(|v| level(v) as u8);
+                levels.compact();
+                Levels::Bits8(levels)
+            }
+            Precision::Bits16 => {
+                let mut levels = matrix.map(|v| level(v) as u16);
+                levels.compact();
+                Levels::Bits16(levels)
+            }
+            Precision::Exact => unreachable!(),
+        };
+        QuantizedDensity {
+            min,
+            step,
+            max_error: quantization_error(min, max, step),
+            levels,
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        match self.levels {
+            Levels::Bits8(_) => return Precision::Bits8,
+            Levels::Bits16(_) => return Precision::Bits16,
+            Levels::Exact(_) => return Precision::Exact,
+        }
+    }
+
+    /// Largest difference between a decoded sample and the original.
+    pub fn max_error(&self) -> f32 {
+        return self.max_error;
+    }
+
+    /// Approximate bytes used by the encoding.
+    pub fn memory(&self) -> usize {
+        let levels = match &self.levels {
+            Levels::Bits8(levels) => levels.memory(),
+            Levels::Bits16(levels) => levels.memory(),
+            Levels::Exact(samples) => samples.memory(),
+        };
+        return levels + 3 * size_of::<f32>();
+    }
+
+    /// Decodes the samples. The matrix keeps the compact representation of the levels.
+    pub fn to_matrix(&self) -> Matrix3D<f32> {
+        match &self.levels {
+            Levels::Bits8(levels) => return levels.map(|l| self.min + *l as f32 * self.step),
+            Levels::Bits16(levels) => return levels.map(|l| self.min + *l as f32 * self.step),
+            Levels::Exact(samples) => return samples.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amethyst::core::math::Vector3;
+
+    /// A smooth field spanning `offset - 3` to `offset + 3`, with constant stretches.
+    fn field(offset: f32) -> Matrix3D<f32> {
+        return Matrix3D::from_fn(Vector3::new(17, 17, 17), |p| {
+            let wave = (p.x as f32 * 0.7).sin() + (p.y as f32 * 0.3).cos() * 2.0;
+            if p.z < 4 {
+                offset
+            } else {
+                offset + wave * (p.z as f32 / 16.0)
+            }
+        });
+    }
+
+    fn largest_error(original: &Matrix3D<f32>, quantized: &QuantizedDensity) -> f32 {
+        return original
+            .values()
+            .zip(quantized.to_matrix().values())
+            .map(|(o, d)| (o - d).abs())
+            .fold(0.0, f32::max);
+    }
+
+    #[test]
+    fn decoded_samples_stay_within_the_stated_error() {
+        // Far from zero, float rounding is a noticeable part of the error.
+        for offset in &[0.0, -0.5, 1000.0, -4096.25] {
+            let original = field(*offset);
+            for precision in &[Precision::Bits8, Precision::Bits16, Precision::Exact] {
+                let quantized = QuantizedDensity::new(&original, *precision);
+                assert_eq!(quantized.precision(), *precision);
+                let error = largest_error(&original, &quantized);
+                assert!(
+                    error <= quantized.max_error(),
+                    "{:?} at {}: {} > {}",
+                    precision,
+                    offset,
+                    error,
+                    quantized.max_error()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_stated_error_is_about_half_a_level() {
+        let original = field(0.0);
+        let (min, max) = range(&original);
+        let half_level = (max - min) / std::u16::MAX as f32 / 2.0;
+        let quantized = QuantizedDensity::new(&original, Precision::Bits16);
+        assert!(quantized.max_error() >= half_level);
+        // The rounding allowance is small next to a 16 bit level of a field this size.
+        assert!(quantized.max_error() < half_level * 1.1);
+    }
+
+    #[test]
+    fn within_picks_the_smallest_precision_meeting_the_bound() {
+        let original = field(0.0);
+        let bits8 = QuantizedDensity::new(&original, Precision::Bits8).max_error();
+        let bits16 = QuantizedDensity::new(&original, Precision::Bits16).max_error();
+        let cases = [
+            (bits8, Precision::Bits8),
+            (bits8 * 0.99, Precision::Bits16),
+            (bits16, Precision::Bits16),
+            (bits16 * 0.99, Precision::Exact),
+            (0.0, Precision::Exact),
+        ];
+        for (max_error, precision) in cases.iter() {
+            let quantized = QuantizedDensity::within(&original, *max_error);
+            assert_eq!(quantized.precision(), *precision, "within {}", max_error);
+            assert!(largest_error(&original, &quantized) <= *max_error);
+        }
+        assert_eq!(QuantizedDensity::within(&original, 0.0).to_matrix(), original);
+    }
+
+    #[test]
+    fn constant_density_decodes_exactly() {
+        let original = Matrix3D::from_fn(Vector3::new(9, 9, 9), |_| -0.75);
+        for precision in &[Precision::Bits8, Precision::Bits16, Precision::Exact] {
+            let quantized = QuantizedDensity::new(&original, *precision);
+            assert_eq!(quantized.to_matrix(), original);
+            assert!(quantized.memory() < 64);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::{
+    camera_path::{CameraCurves, CameraPath},
+    character_config::CharacterConfig,
+    components::{CameraBoom, CameraBoomHandle, ChunkLoader, OnCameraPath, Spectator},
+    floating_origin::FloatingOrigin,
+    input::{ActionBinding, AxisBinding, GameBindings},
+    states::RunState,
+    Terrain,
+};
+use amethyst::{
+    core::{
+        math::{UnitQuaternion, Vector3},
+        Parent, Time, Transform,
+    },
+    ecs::prelude::*,
+    input::{InputEvent, InputHandler},
+    renderer::{ActiveCamera, Camera},
+    shrev::EventChannel,
+};
+use log::{info, warn};
+use std::{path::PathBuf, sync::Arc};
+
+/// Detaches the view from the character on `ToggleSpectator`, and flies it freely:
+/// no physics or collision, and chunks stream around it instead of only the character.
+///
+/// The spectator is a separate camera entity starting where the character's camera
+/// was. Toggling back deletes it and renders from the character's camera again,
+/// which never moved. `ZoomIn` and `ZoomOut` change the flying speed.
+///
+/// `PlayCameraPath` flies the spectator along the `CameraPath` in `path_file`
+/// instead, and `RecordKeyframe` appends the current view to `recording_file`.
+/// Recordings go to their own file, as saving one rewrites it without comments.
+pub struct SpectatorSystem {
+    path_file: PathBuf,
+    recording_file: PathBuf,
+    /// The camera path being played, and how many seconds into it.
+    playback: Option<(CameraCurves, f32)>,
+    input_event_reader: Option<ReaderId<InputEvent<GameBindings>>>,
+}
+
+impl SpectatorSystem {
+    pub fn new(path_file: PathBuf, recording_file: PathBuf) -> Self {
+        SpectatorSystem {
+            path_file,
+            recording_file,
+            playback: None,
+            input_event_reader: None,
+        }
+    }
+
+    /// Appends `posn` and `rotation` to the recording, creating it if needed. A new
+    /// recording takes its interpolation and key interval from the played path.
+    fn record_keyframe(&self, posn: Vector3<f64>, rotation: UnitQuaternion<f32>) {
+        let mut path = if self.recording_file.exists() {
+            match CameraPath::load(&self.recording_file) {
+                Ok(path) => path,
+                Err(e) => {
+                    // Better to lose the keyframe than the recording.
+                    warn!("not recording keyframe: {}", e);
+                    return;
+                }
+            }
+        } else {
+            CameraPath {
+                keys: vec![],
+                ..CameraPath::load(&self.path_file).unwrap_or_default()
+            }
+        };
+        path.push(posn, rotation);
+        match path.save(&self.recording_file) {
+            Ok(()) => info!(
+                "recorded keyframe {} of {:?}",
+                path.keys.len(),
+                self.recording_file
+            ),
+            Err(e) => warn!("could not save camera path: {}", e),
+        }
+    }
+}
+
+impl<'s> System<'s> for SpectatorSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        Entities<'s>,
+        Read<'s, Time>,
+        Read<'s, RunState>,
+        ReadExpect<'s, CharacterConfig>,
+        Read<'s, EventChannel<InputEvent<GameBindings>>>,
+        Read<'s, InputHandler<GameBindings>>,
+        Read<'s, Arc<Terrain>>,
+        Read<'s, FloatingOrigin>,
+        Write<'s, ActiveCamera>,
+        ReadStorage<'s, CameraBoom>,
+        ReadStorage<'s, CameraBoomHandle>,
+        ReadStorage<'s, Parent>,
+        WriteStorage<'s, Spectator>,
+        WriteStorage<'s, OnCameraPath>,
+        WriteStorage<'s, ChunkLoader>,
+        WriteStorage<'s, Camera>,
+        WriteStorage<'s, Transform>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            time,
+            run_state,
+            config,
+            input_event_channel,
+            input,
+            terrain,
+            origin,
+            mut active_camera,
+            booms,
+            handles,
+            parents,
+            mut spectators,
+            mut on_path,
+            mut loaders,
+            mut cameras,
+            mut transforms,
+        ): Self::SystemData,
+    ) {
+        let mut toggle = false;
+        let mut play = false;
+        let mut record = false;
+        let mut speed_change = 1.0;
+        for e in input_event_channel.read(self.input_event_reader.as_mut().unwrap()) {
+            match e {
+                InputEvent::ActionPressed(ActionBinding::ToggleSpectator) => toggle = !toggle,
+                InputEvent::ActionPressed(ActionBinding::PlayCameraPath) => play = !play,
+                InputEvent::ActionPressed(ActionBinding::RecordKeyframe) => record = true,
+                InputEvent::ActionPressed(ActionBinding::ZoomIn) => {
+                    speed_change *= config.spectator_speed_step
+                }
+                InputEvent::ActionPressed(ActionBinding::ZoomOut) => {
+                    speed_change /= config.spectator_speed_step
+                }
+                _ => {}
+            }
+        }
+        if *run_state != RunState::Playing {
+            return;
+        }
+
+        let chunk_size = terrain.chunk_size();
+        let character_camera = (&entities, &booms).join().map(|(e, _)| e).next();
+        let mut spectator = (&entities, &spectators).join().map(|(e, _)| e).next();
+
+        if record {
+            let pose = match spectator.and_then(|e| transforms.get(e)) {
+                Some(transform) => Some((*transform.translation(), *transform.rotation())),
+                None => character_camera
+                    .and_then(|camera| character_pose(camera, &handles, &parents, &transforms)),
+            };
+            if let Some((posn, rotation)) = pose {
+                self.record_keyframe(origin.absolute(&posn, chunk_size), rotation);
+            }
+        }
+
+        if toggle {
+            if let Some(current) = spectator.take() {
+                entities.delete(current).unwrap();
+                active_camera.entity = character_camera;
+                self.playback = None;
+                info!("back to the character");
+            } else if let Some(camera) = character_camera {
+                spectator = spawn(
+                    &entities,
+                    camera,
+                    &config,
+                    &handles,
+                    &parents,
+                    &mut spectators,
+                    &mut loaders,
+                    &mut cameras,
+                    &mut transforms,
+                );
+                active_camera.entity = spectator;
+                info!("spectating");
+            }
+        }
+
+        if play {
+            if self.playback.take().is_some() {
+                info!("stopped the camera path");
+            } else {
+                let curves = CameraPath::load(&self.path_file)
+                    .map(|path| path.curves())
+                    .unwrap_or_else(|e| {
+                        warn!("can't play the camera path: {}", e);
+                        None
+                    });
+                // The path is flown by a spectator, so chunks stream along it.
+                if curves.is_some() && spectator.is_none() {
+                    if let Some(camera) = character_camera {
+                        spectator = spawn(
+                            &entities,
+                            camera,
+                            &config,
+                            &handles,
+                            &parents,
+                            &mut spectators,
+                            &mut loaders,
+                            &mut cameras,
+                            &mut transforms,
+                        );
+                        active_camera.entity = spectator;
+                    }
+                }
+                if let (Some(curves), Some(_)) = (curves, spectator) {
+                    info!(
+                        "playing the {:.1} second camera path in {:?}",
+                        curves.duration(),
+                        self.path_file
+                    );
+                    self.playback = Some((curves, 0.0));
+                }
+            }
+        }
+
+        if let Some((curves, elapsed)) = &mut self.playback {
+            *elapsed += time.delta_seconds();
+            let (posn, rotation) = curves.sample(*elapsed);
+            if let Some(transform) = spectator.and_then(|e| transforms.get_mut(e)) {
+                transform.set_translation(origin.relative(&posn, chunk_size));
+                transform.set_rotation(rotation);
+            }
+            if *elapsed >= curves.duration() {
+                info!("finished the camera path");
+                self.playback = None;
+            }
+        }
+        // Mouse and stick input leave the spectator alone while it follows the path.
+        for (e, _) in (&entities, &spectators).join() {
+            if self.playback.is_some() {
+                on_path.insert(e, OnCameraPath).unwrap();
+            } else {
+                on_path.remove(e);
+            }
+        }
+        if self.playback.is_some() {
+            return;
+        }
+
+        let is_down = |action: ActionBinding| input.action_is_down(&action).unwrap_or(false);
+        let vertical_input = match (is_down(ActionBinding::Jump), is_down(ActionBinding::Descend)) {
+            (true, false) => 1.0,
+            (false, true) => -1.0,
+            _ => 0.0,
+        };
+        for (transform, spectator) in (&mut transforms, &mut spectators).join() {
+            spectator.speed = (spectator.speed * speed_change)
+                .max(config.min_spectator_speed)
+                .min(config.max_spectator_speed);
+
+            // Fly where the camera looks, and straight up or down.
+            let mut direction = transform.rotation()
+                * Vector3::new(
+                    input.axis_value(&AxisBinding::MoveRight).unwrap_or(0.0),
+                    0.0,
+                    -input.axis_value(&AxisBinding::MoveForward).unwrap_or(0.0),
+                );
+            direction.y += vertical_input;
+            if direction.norm() > 1.0 {
+                direction = direction.normalize();
+            }
+            let speed = if is_down(ActionBinding::Sprint) {
+                spectator.speed * config.sprint_multiplier
+            } else {
+                spectator.speed
+            };
+            transform.prepend_translation(direction * speed * time.delta_seconds());
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        let mut ie = world.fetch_mut::<EventChannel<InputEvent<GameBindings>>>();
+        self.input_event_reader = Some(ie.register_reader());
+    }
+}
+
+/// Where the character's `camera` is and which way it looks.
+fn character_pose(
+    camera: Entity,
+    handles: &ReadStorage<CameraBoomHandle>,
+    parents: &ReadStorage<Parent>,
+    transforms: &WriteStorage<Transform>,
+) -> Option<(Vector3<f32>, UnitQuaternion<f32>)> {
+    let global = transforms.get(camera)?.global_matrix();
+    let posn = Vector3::new(global[(0, 3)], global[(1, 3)], global[(2, 3)]);
+    // The camera itself never turns; its boom handle does, on a character whose
+    // rotation is locked.
+    let rotation = parents
+        .get(camera)
+        .filter(|parent| handles.contains(parent.entity))
+        .and_then(|parent| transforms.get(parent.entity))
+        .map(|handle| *handle.rotation())?;
+    return Some((posn, rotation));
+}
+
+/// Creates a spectator looking through `camera`, loading chunks as far out as
+/// the character does but ahead of it.
+#[allow(clippy::too_many_arguments)]
+fn spawn(
+    entities: &Entities,
+    camera: Entity,
+    config: &CharacterConfig,
+    handles: &ReadStorage<CameraBoomHandle>,
+    parents: &ReadStorage<Parent>,
+    spectators: &mut WriteStorage<Spectator>,
+    loaders: &mut WriteStorage<ChunkLoader>,
+    cameras: &mut WriteStorage<Camera>,
+    transforms: &mut WriteStorage<Transform>,
+) -> Option<Entity> {
+    let projection = cameras.get(camera)?.clone();
+    let (posn, rotation) = character_pose(camera, handles, parents, transforms)?;
+    let loader = (&*loaders)
+        .join()
+        .max_by_key(|loader| loader.priority)
+        .map(|loader| ChunkLoader {
+            radius: loader.radius,
+            priority: loader.priority + 1,
+        })?;
+
+    let mut transform = Transform::default();
+    transform.set_translation(posn);
+    transform.set_rotation(rotation);
+    let spectator = entities
+        .build_entity()
+        .with(transform, transforms)
+        .with(projection, cameras)
+        .with(loader, loaders)
+        .with(
+            Spectator {
+                speed: config.spectator_speed,
+            },
+            spectators,
+        )
+        .build();
+    return Some(spectator);
+}
@@ -0,0 +1,213 @@
+use crate::{
+    chunk_registry::ChunkRegistry,
+    chunk_scheduler::{self, LoaderRegion},
+    components::ChunkLoader,
+    floating_origin::FloatingOrigin,
+    terrain::TerrainConfig,
+    terrain_materials::TerrainMaterials,
+    Terrain,
+};
+use amethyst::{
+    core::{math::Vector3, transform::Transform, Time},
+    input::{is_close_requested, is_key_down, VirtualKeyCode},
+    prelude::*,
+    renderer::palette::Srgb,
+    utils::application_root_dir,
+    winit::Window,
+};
+use log::{info, warn};
+use rand::prelude::*;
+use std::sync::Arc;
+
+/// Which state the game is in, for systems that should stop outside of `Playing`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RunState {
+    Loading,
+    Playing,
+    Paused,
+}
+
+impl Default for RunState {
+    fn default() -> Self {
+        RunState::Loading
+    }
+}
+
+/// Grabs and hides the cursor, or releases it.
+fn grab_cursor(world: &World, grab: bool) {
+    let window = world.read_resource::<Window>();
+    if let Err(e) = window.grab_cursor(grab) {
+        warn!("could not grab the cursor: {}", e);
+    }
+    window.hide_cursor(grab);
+}
+
+/// Creates the terrain and generates the chunks around the spawn point before the
+/// character is created, so it doesn't fall through terrain that isn't there yet.
+#[derive(Default)]
+pub struct LoadingState {
+    /// Keeps the spawn chunks loaded until the character's own `ChunkLoader` takes over.
+    loader: Option<Entity>,
+    /// Last reported progress, in tenths.
+    reported: Option<usize>,
+}
+
+impl SimpleState for LoadingState {
+    fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        let world = data.world;
+        world.insert(RunState::Loading);
+
+        // Add light
+        crate::add_light_entity(
+            world,
+            Srgb::new(1.0, 1.0, 1.0),
+            Vector3::new(0.0, -1.0, 0.0),
+            1.0,
+        );
+
+        // Create terrain
+        let terrain = {
+            let terrain_config = world.read_resource::<TerrainConfig>();
+            terrain_config.build(terrain_config.seed.map(u128::from).unwrap_or_else(random))
+        };
+        world.insert(Arc::new(terrain));
+        world.register::<crate::components::Chunk>();
+
+        let materials_path = application_root_dir()
+            .unwrap()
+            .join("config")
+            .join("terrain_materials.ron");
+        let terrain_materials = TerrainMaterials::load(world, materials_path);
+        world.insert(terrain_materials);
+
+        let loader = ChunkLoader {
+            radius: crate::LOAD_RADIUS,
+            priority: 0,
+        };
+        // Queue the spawn chunks right away, so progress is known from the first frame.
+        {
+            let terrain = world.read_resource::<Arc<Terrain>>();
+            let origin = world.read_resource::<FloatingOrigin>();
+            let region = LoaderRegion::new(
+                Vector3::from(crate::SPAWN_POSITION),
+                &loader,
+                terrain.chunk_size(),
+                &origin,
+            );
+            chunk_scheduler::queue_regions(
+                &mut world.write_resource::<ChunkRegistry>(),
+                &terrain,
+                &[region],
+            );
+        }
+        let mut transform = Transform::default();
+        transform.set_translation(Vector3::from(crate::SPAWN_POSITION));
+        self.loader = Some(world.create_entity().with(transform).with(loader).build());
+    }
+
+    fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+        let (loaded, total) = {
+            let registry = data.world.read_resource::<ChunkRegistry>();
+            let total = registry.len();
+            let loaded = registry
+                .iter()
+                .filter(|(coord, _)| registry.is_loaded(coord))
+                .count();
+            (loaded, total)
+        };
+        let tenths = if total == 0 { 10 } else { loaded * 10 / total };
+        if self.reported != Some(tenths) {
+            self.reported = Some(tenths);
+            info!("loading terrain: {}/{} chunks", loaded, total);
+        }
+        if loaded < total {
+            return Trans::None;
+        }
+
+        if let Some(loader) = self.loader.take() {
+            data.world.delete_entity(loader).unwrap();
+        }
+        // Create the character + camera.
+        crate::create_character_entity(data.world);
+        return Trans::Switch(Box::new(PlayingState));
+    }
+
+    fn handle_event(
+        &mut self,
+        _data: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        if let StateEvent::Window(event) = &event {
+            if is_close_requested(event) {
+                return Trans::Quit;
+            }
+        }
+        return Trans::None;
+    }
+}
+
+/// The character is controlled, with the cursor grabbed and hidden.
+/// Escape pauses.
+pub struct PlayingState;
+
+impl SimpleState for PlayingState {
+    fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        info!("playing");
+        data.world.insert(RunState::Playing);
+        grab_cursor(data.world, true);
+    }
+
+    fn on_resume(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        self.on_start(data);
+    }
+
+    fn handle_event(
+        &mut self,
+        _data: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        if let StateEvent::Window(event) = &event {
+            if is_close_requested(event) {
+                return Trans::Quit;
+            }
+            if is_key_down(event, VirtualKeyCode::Escape) {
+                return Trans::Push(Box::new(PausedState));
+            }
+        }
+        return Trans::None;
+    }
+}
+
+/// Stops physics and chunk streaming by stopping time, and releases the cursor.
+/// Escape resumes.
+pub struct PausedState;
+
+impl SimpleState for PausedState {
+    fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        info!("paused");
+        data.world.insert(RunState::Paused);
+        // Physics only steps when time passes, so no time passing means no steps.
+        data.world.write_resource::<Time>().set_time_scale(0.0);
+        grab_cursor(data.world, false);
+    }
+
+    fn on_stop(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+        data.world.write_resource::<Time>().set_time_scale(1.0);
+    }
+
+    fn handle_event(
+        &mut self,
+        _data: StateData<'_, GameData<'_, '_>>,
+        event: StateEvent,
+    ) -> SimpleTrans {
+        if let StateEvent::Window(event) = &event {
+            if is_close_requested(event) {
+                return Trans::Quit;
+            }
+            if is_key_down(event, VirtualKeyCode::Escape) {
+                return Trans::Pop;
+            }
+        }
+        return Trans::None;
+    }
+}
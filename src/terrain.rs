@@ -1,18 +1,73 @@
-use crate::{marching_cubes, matrix_3d::Matrix3D};
+use crate::{chunk_registry::ChunkCoord, marching_cubes, matrix_3d::Matrix3D};
 use amethyst::core::math::Vector3;
-use marching_cubes::{MeshData, TriangulationMethod};
+use marching_cubes::{MeshData, TriangulationMethod, CUTOFF};
 use noise::{NoiseFn, OpenSimplex, Seedable};
 use rand::{prelude::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
 use splines::{Interpolation, Key, Spline};
 
+/// OpenSimplex is meant to stay within [-1, 1], but it's not an exact bound,
+/// so the largest value the noise is assumed to reach is padded by this much.
+const NOISE_MARGIN: f32 = 1.1;
+
+/// Whether a region of density is certain to lie on one side of the iso-surface.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Homogeneity {
+    /// Entirely above the surface.
+    Empty,
+    /// Entirely below the surface.
+    Solid,
+    /// May contain the surface.
+    Mixed,
+}
+
+#[derive(Clone)]
 pub struct Terrain {
     noise: Vec<OpenSimplex>,
     noise_weights: Vec<f32>,
     noise_scales: Vec<f32>,
     upper_bound: Spline<f32, f32>,
     lower_bound: Spline<f32, f32>,
+    /// Heights of the first and last spline keys; the bounds are constant outside of them.
+    bound_heights: (f32, f32),
     points_per_chunk: u8,
     scale: f32,
+    /// Noise is evaluated every `interpolation` points and interpolated in between.
+    interpolation: usize,
+    /// Extra samples around each side of a chunk, for gradients across its border.
+    apron: usize,
+}
+
+/// Parameters the `Terrain` is generated from.
+#[derive(Deserialize, Clone)]
+pub struct TerrainConfig {
+    /// `None` picks a random seed every run.
+    pub seed: Option<u64>,
+    pub points_per_chunk: u8,
+    pub scale: f32,
+    pub noise_weights: Vec<f32>,
+    pub noise_scales: Vec<f32>,
+    /// Noise is evaluated on a lattice this many points apart and trilinearly
+    /// interpolated in between. 1 evaluates it at every point.
+    pub interpolation: usize,
+    /// Samples added beyond each face of a chunk. Meshing only covers the chunk
+    /// itself, while normals are taken from the density gradient, which needs
+    /// an apron of at least 1 to match across chunk borders.
+    pub apron: usize,
+}
+
+impl TerrainConfig {
+    pub fn build(&self, seed: u128) -> Terrain {
+        return Terrain::new(
+            seed,
+            self.points_per_chunk,
+            self.scale,
+            self.noise_weights.clone(),
+            self.noise_scales.clone(),
+        )
+        .with_interpolation(self.interpolation)
+        .with_apron(self.apron);
+    }
 }
 
 impl std::default::Default for Terrain {
@@ -69,58 +124,242 @@ impl Terrain {
             noise_scales,
             upper_bound,
             lower_bound,
+            bound_heights: (floor, air),
             points_per_chunk,
             scale,
+            interpolation: 1,
+            apron: 0,
         }
     }
 
-    fn true_chunk(&self, chunk: &Vector3<isize>) -> Vector3<f32> {
-        return Vector3::new(chunk.x as f32, chunk.y as f32, chunk.z as f32)
-            .scale(self.points_per_chunk as f32 * self.scale);
+    pub fn with_interpolation(mut self, factor: usize) -> Self {
+        self.interpolation = factor.max(1);
+        return self;
     }
 
-    fn true_coord(&self, true_chunk: &Vector3<f32>, posn: &Vector3<usize>) -> Vector3<f32> {
-        return true_chunk
-            + Vector3::new(posn.x as f32, posn.y as f32, posn.z as f32).scale(self.scale);
+    pub fn with_apron(mut self, apron: usize) -> Self {
+        self.apron = apron;
+        return self;
     }
 
-    fn get_matrix(&self, chunk: &Vector3<isize>) -> Matrix3D {
-        let points = self.points_per_chunk as usize + 1;
-        let mut matrix = Matrix3D::new(Vector3::new(points, points, points));
+    /// Cheaply checks whether every chunk whose y coordinate is within
+    /// `min_chunk_y..=max_chunk_y` is entirely above or below the surface, in
+    /// which case none of them need to be sampled or meshed. The noise isn't
+    /// sampled: only the height bounds at each row and the largest value the
+    /// noise layers can add up to are considered, so any box of chunks spanning
+    /// those rows is classified the same.
+    pub fn classify_rows(&self, min_chunk_y: i64, max_chunk_y: i64) -> Homogeneity {
+        let (min, max) = self.density_bounds(min_chunk_y, max_chunk_y);
+        if min > CUTOFF {
+            return Homogeneity::Empty;
+        } else if max < CUTOFF {
+            return Homogeneity::Solid;
+        }
+        return Homogeneity::Mixed;
+    }
+
+    /// Cheaply checks whether a chunk is entirely above or below the surface.
+    pub fn classify_chunk(&self, chunk: &ChunkCoord) -> Homogeneity {
+        return self.classify_rows(chunk.y, chunk.y);
+    }
+
+    /// Lower and upper bound of the density sampled in the given chunk rows.
+    fn density_bounds(&self, min_chunk_y: i64, max_chunk_y: i64) -> (f32, f32) {
+        let amplitude: f32 =
+            self.noise_weights.iter().map(|w| w.abs()).sum::<f32>() * NOISE_MARGIN;
+        let points = self.points_per_chunk as i64;
+
+        // Past the outermost keys the bounds don't change, so a single row
+        // outside of them stands in for all the others.
+        let key_first = (self.bound_heights.0 / self.scale).floor() as i64 - 1;
+        let key_last = (self.bound_heights.1 / self.scale).ceil() as i64 + 1;
+        let first = (min_chunk_y * points).max(key_first).min(key_last);
+        let last = ((max_chunk_y + 1) * points).max(key_first).min(key_last);
+
+        let mut min = std::f32::INFINITY;
+        let mut max = std::f32::NEG_INFINITY;
+        for row in first..(last + 1) {
+            let height = row as f32 * self.scale;
+            let upper_bound = self.upper_bound.clamped_sample(height).unwrap();
+            let lower_bound = self.lower_bound.clamped_sample(height).unwrap();
+            let diff = upper_bound - lower_bound;
+            // The density is linear in the noise value, so its extremes are at the noise's extremes.
+            for val in &[-amplitude, amplitude] {
+                let adjusted_val = (val - (-1.0)) * 0.5 * diff + lower_bound;
+                min = min.min(adjusted_val);
+                max = max.max(adjusted_val);
+            }
+        }
+        return (min, max);
+    }
+
+    /// Absolute index, counted in points from the world's origin, of the `i`th sample
+    /// along an axis of the chunk at `chunk` on that axis. The first `apron` samples
+    /// lie before the chunk.
+    fn point_index(&self, chunk: i64, i: usize, step: usize) -> i64 {
+        return chunk * self.points_per_chunk as i64
+            + (i as i64 - self.apron as i64) * step as i64;
+    }
+
+    // Absolute coordinates are kept in f64, so chunks far from the origin
+    // sample the noise as precisely as the ones next to it.
+    fn true_coord(&self, index: &Vector3<i64>) -> Vector3<f64> {
+        return Vector3::new(index.x as f64, index.y as f64, index.z as f64)
+            .scale(self.scale as f64);
+    }
+
+    /// Samples the density of a chunk every `step` points, plus `apron` samples
+    /// beyond each face. With a `step` that doesn't divide `points_per_chunk`,
+    /// the last samples reach slightly past the end of the chunk.
+    fn get_matrix(&self, chunk: &ChunkCoord, step: usize) -> Matrix3D<f32> {
+        let points = (self.points_per_chunk as usize + step - 1) / step + 1 + 2 * self.apron;
+        let mut matrix = if self.interpolation > 1 {
+            self.interpolated_noise(chunk, points, step)
+        } else {
+            self.exact_noise(chunk, points, step)
+        };
 
-        let true_chunk = self.true_chunk(chunk);
-        for z in 0..points {
-            for y in 0..points {
+        // The bounds only depend on height, so they are sampled once per row.
+        for y in 0..points {
+            let height = self.point_index(chunk.y, y, step) as f32 * self.scale;
+            let upper_bound = self.upper_bound.clamped_sample(height).unwrap();
+            let lower_bound = self.lower_bound.clamped_sample(height).unwrap();
+            let diff = upper_bound - lower_bound;
+            for z in 0..points {
                 for x in 0..points {
-                    let true_coord: Vector3<f32> =
-                        self.true_coord(&true_chunk, &Vector3::new(x, y, z));
-                    let mut val = 0.0;
-                    for i in 0..self.noise.len() {
-                        val += self.noise[i].get([
-                            (true_coord.x * self.noise_scales[i]) as f64,
-                            (true_coord.y * self.noise_scales[i]) as f64,
-                            (true_coord.z * self.noise_scales[i]) as f64,
-                        ]) as f32
-                            * self.noise_weights[i];
-                    }
-
-                    let upper_bound = self.upper_bound.clamped_sample(true_coord.y).unwrap();
-                    let lower_bound = self.lower_bound.clamped_sample(true_coord.y).unwrap();
-                    let diff = upper_bound - lower_bound;
-                    let adjusted_val = (val - (-1.0)) * 0.5 * diff + lower_bound;
-                    matrix.set(Vector3::new(x, y, z), adjusted_val);
+                    let posn = Vector3::new(x, y, z);
+                    matrix.set(posn, (matrix[posn] - (-1.0)) * 0.5 * diff + lower_bound);
                 }
             }
         }
         return matrix;
     }
 
-    pub fn get_chunk(&self, chunk: &Vector3<isize>) -> MeshData {
+    /// Exact density at an absolute position, in world units counted from chunk zero.
+    /// Ignores `interpolation`, so it may differ slightly from sampled chunks.
+    pub fn density_at(&self, posn: &Vector3<f64>) -> f32 {
+        let height = posn.y as f32;
+        let upper_bound = self.upper_bound.clamped_sample(height).unwrap();
+        let lower_bound = self.lower_bound.clamped_sample(height).unwrap();
+        let diff = upper_bound - lower_bound;
+        return (self.noise_at(posn) - (-1.0)) * 0.5 * diff + lower_bound;
+    }
+
+    fn noise_at(&self, true_coord: &Vector3<f64>) -> f32 {
+        let mut val = 0.0;
+        for i in 0..self.noise.len() {
+            val += self.noise[i].get([
+                true_coord.x * self.noise_scales[i] as f64,
+                true_coord.y * self.noise_scales[i] as f64,
+                true_coord.z * self.noise_scales[i] as f64,
+            ]) as f32
+                * self.noise_weights[i];
+        }
+        return val;
+    }
+
+    fn exact_noise(&self, chunk: &ChunkCoord, points: usize, step: usize) -> Matrix3D<f32> {
+        return Matrix3D::from_fn(Vector3::new(points, points, points), |posn| {
+            self.noise_at(&self.true_coord(&Vector3::new(
+                self.point_index(chunk.x, posn.x, step),
+                self.point_index(chunk.y, posn.y, step),
+                self.point_index(chunk.z, posn.z, step),
+            )))
+        });
+    }
+
+    /// Evaluates the noise on a lattice `interpolation` points apart and trilinearly
+    /// interpolates the samples in between. The lattice is aligned to absolute
+    /// coordinates, so neighbouring chunks agree on the samples they share.
+    fn interpolated_noise(&self, chunk: &ChunkCoord, points: usize, step: usize) -> Matrix3D<f32> {
+        let factor = self.interpolation as i64;
+
+        // Per axis: the first lattice point, then for every sample the lattice
+        // cell it lies in and how far along that cell it is.
+        let axis = |chunk: i64| -> (i64, Vec<(usize, f32)>) {
+            let lattice_min = self.point_index(chunk, 0, step).div_euclid(factor);
+            let cells = (0..points)
+                .map(|i| {
+                    let point = self.point_index(chunk, i, step);
+                    (
+                        (point.div_euclid(factor) - lattice_min) as usize,
+                        point.rem_euclid(factor) as f32 / factor as f32,
+                    )
+                })
+                .collect();
+            (lattice_min, cells)
+        };
+        let (min_x, cells_x) = axis(chunk.x);
+        let (min_y, cells_y) = axis(chunk.y);
+        let (min_z, cells_z) = axis(chunk.z);
+        let lattice_len = |cells: &Vec<(usize, f32)>| cells.last().unwrap().0 + 2;
+        let dim = Vector3::new(
+            lattice_len(&cells_x),
+            lattice_len(&cells_y),
+            lattice_len(&cells_z),
+        );
+
+        let spacing = (factor as f64) * self.scale as f64;
+        let lattice = Matrix3D::from_fn(dim, |posn| {
+            self.noise_at(&Vector3::new(
+                (min_x + posn.x as i64) as f64 * spacing,
+                (min_y + posn.y as i64) as f64 * spacing,
+                (min_z + posn.z as i64) as f64 * spacing,
+            ))
+        });
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        return Matrix3D::from_fn(Vector3::new(points, points, points), |posn| {
+            let (cx, tx) = cells_x[posn.x];
+            let (cy, ty) = cells_y[posn.y];
+            let (cz, tz) = cells_z[posn.z];
+            let corner = |dx: usize, dy: usize, dz: usize| {
+                lattice[Vector3::new(cx + dx, cy + dy, cz + dz)]
+            };
+            let c00 = lerp(corner(0, 0, 0), corner(1, 0, 0), tx);
+            let c10 = lerp(corner(0, 1, 0), corner(1, 1, 0), tx);
+            let c01 = lerp(corner(0, 0, 1), corner(1, 0, 1), tx);
+            let c11 = lerp(corner(0, 1, 1), corner(1, 1, 1), tx);
+            return lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz);
+        });
+    }
+
+    /// Samples the density of a chunk at full resolution.
+    pub fn sample_chunk(&self, chunk: &ChunkCoord) -> Matrix3D<f32> {
+        return self.get_matrix(chunk, 1);
+    }
+
+    /// Meshes density sampled by `sample_chunk`, leaving out the apron.
+    pub fn mesh_chunk(&self, matrix: &Matrix3D<f32>) -> MeshData {
         return marching_cubes::get_mesh_data(
-            &self.get_matrix(chunk),
+            matrix,
             self.scale,
             TriangulationMethod::BASIC,
             true,
+            self.apron,
+        );
+    }
+
+    /// The part of density sampled by `sample_chunk` that lies within the chunk,
+    /// without the apron.
+    pub fn chunk_interior(&self, matrix: &Matrix3D<f32>) -> Matrix3D<f32> {
+        let points = self.points_per_chunk as usize + 1;
+        return matrix
+            .view(Vector3::repeat(self.apron), Vector3::repeat(points))
+            .unwrap()
+            .to_matrix();
+    }
+
+    /// Meshes a chunk's interior density at a coarser resolution, keeping every
+    /// `step`th point. The chunk's corners stay where they are.
+    pub fn mesh_downsampled(&self, density: &Matrix3D<f32>, step: usize) -> MeshData {
+        let points = (self.points_per_chunk as usize + step - 1) / step + 1;
+        return marching_cubes::get_mesh_data(
+            &density.resample(Vector3::repeat(points)),
+            self.chunk_size() / (points - 1) as f32,
+            TriangulationMethod::BASIC,
+            true,
+            0,
         );
     }
 
@@ -0,0 +1,392 @@
+use crate::{
+    chunk_registry::ChunkCoord, chunk_system::ChunkEvent, components::Chunk,
+    quantized_density::QuantizedDensity, workers::WorkerPool, Terrain,
+};
+use amethyst::{
+    core::{
+        math::{Point3, Vector3},
+        Transform,
+    },
+    ecs::prelude::*,
+    renderer::{debug_drawing::DebugLines, palette::Srgba},
+    shrev::EventChannel,
+};
+use amethyst_physics::{prelude::*, servers::BodyMode};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+/// Where a chunk's collision mesh comes from.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum CollisionMesh {
+    /// The same mesh that is rendered.
+    Render,
+    /// Marching cubes over the chunk's kept density, keeping every n points
+    /// (2 for half, 4 for quarter resolution).
+    Downsampled(usize),
+    /// The render mesh with vertices closer than `tolerance` merged together.
+    Simplified,
+}
+
+impl CollisionMesh {
+    /// Whether colliders are built from the render mesh, which chunks then keep around.
+    pub fn uses_render_mesh(self) -> bool {
+        match self {
+            CollisionMesh::Render | CollisionMesh::Simplified => return true,
+            CollisionMesh::Downsampled(_) => return false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CollisionConfig {
+    /// Chunks closer than this (in world units) to a dynamic body get a collider.
+    pub radius: f32,
+    /// Threads used to build collision meshes.
+    pub worker_threads: usize,
+    pub mesh: CollisionMesh,
+    /// Size of the cells vertices are merged within, for `CollisionMesh::Simplified`.
+    pub tolerance: f32,
+    /// Draw every collider as a wireframe over the terrain.
+    pub debug_draw: bool,
+}
+
+impl Default for CollisionConfig {
+    fn default() -> Self {
+        CollisionConfig {
+            radius: 24.0,
+            worker_threads: 2,
+            mesh: CollisionMesh::Render,
+            tolerance: 0.5,
+            debug_draw: false,
+        }
+    }
+}
+
+/// Wireframes of the current colliders, relative to their chunk, kept while
+/// `CollisionConfig::debug_draw` is set.
+#[derive(Default)]
+pub struct CollisionDebugMeshes {
+    meshes: HashMap<Entity, Vec<(Point3<f32>, Point3<f32>)>>,
+}
+
+/// What a collision mesh is built from.
+enum ColliderSource {
+    /// The chunk's render mesh, as kept in `Chunk::surface`.
+    Surface(Arc<Vec<[f32; 3]>>),
+    /// The chunk's kept density, resampled every `step` points.
+    Density {
+        terrain: Arc<Terrain>,
+        density: Arc<QuantizedDensity>,
+        step: usize,
+    },
+}
+
+struct ColliderJob {
+    entity: Entity,
+    coord: ChunkCoord,
+    source: ColliderSource,
+    /// Size of the cells vertices are merged within; 0 only merges identical positions.
+    tolerance: f32,
+}
+
+struct ColliderResult {
+    entity: Entity,
+    coord: ChunkCoord,
+    points: Vec<Point3<f32>>,
+    indices: Vec<Point3<usize>>,
+}
+
+/// Gives chunks near dynamic bodies a static trimesh collider, and takes it
+/// away again once no body is close. The collision meshes are built on worker
+/// threads; only the physics objects are created on this one.
+pub struct TerrainCollisionSystem {
+    workers: WorkerPool<ColliderJob, ColliderResult>,
+    pending: HashSet<Entity>,
+    deterministic: bool,
+    chunk_event_reader: Option<ReaderId<ChunkEvent>>,
+}
+
+impl TerrainCollisionSystem {
+    pub fn new(config: &CollisionConfig) -> Self {
+        TerrainCollisionSystem {
+            workers: WorkerPool::new(config.worker_threads, build_collider),
+            pending: HashSet::new(),
+            deterministic: false,
+            chunk_event_reader: None,
+        }
+    }
+
+    /// Waits for every collider requested in one step at the start of the next,
+    /// and attaches them in chunk order, so they appear at the same step however
+    /// long the workers take. Needed for input replays.
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        return self;
+    }
+}
+
+impl<'s> System<'s> for TerrainCollisionSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        Entities<'s>,
+        ReadExpect<'s, PhysicsWorld<f32>>,
+        Read<'s, Arc<Terrain>>,
+        ReadExpect<'s, CollisionConfig>,
+        Read<'s, EventChannel<ChunkEvent>>,
+        Write<'s, CollisionDebugMeshes>,
+        ReadStorage<'s, Chunk>,
+        ReadStorage<'s, Transform>,
+        WriteStorage<'s, PhysicsHandle<PhysicsShapeTag>>,
+        WriteStorage<'s, PhysicsHandle<PhysicsRigidBodyTag>>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            physics_world,
+            terrain,
+            config,
+            chunk_events,
+            mut debug_meshes,
+            chunks,
+            transforms,
+            mut shapes,
+            mut rigid_bodies,
+        ): Self::SystemData,
+    ) {
+        // Deleted chunk entities take their physics handles with them, but not their wireframes.
+        for event in chunk_events.read(self.chunk_event_reader.as_mut().unwrap()) {
+            match event {
+                ChunkEvent::Unloaded {
+                    entity: Some(e), ..
+                } => {
+                    debug_meshes.meshes.remove(e);
+                }
+                _ => {}
+            }
+        }
+
+        let chunk_size = terrain.chunk_size();
+        let bodies: Vec<Vector3<f32>> = (&transforms, &rigid_bodies, !&chunks)
+            .join()
+            .map(|(transform, _, _)| *transform.translation())
+            .collect();
+        let closest_body = |origin: &Vector3<f32>| {
+            bodies
+                .iter()
+                .map(|body| distance_to_chunk(body, origin, chunk_size))
+                .fold(std::f32::INFINITY, f32::min)
+        };
+
+        let mut finished = vec![];
+        if self.deterministic {
+            while let Some(result) = self.workers.recv() {
+                finished.push(result);
+            }
+            finished.sort_by_key(|result| (result.coord.x, result.coord.y, result.coord.z));
+        } else {
+            while let Some(result) = self.workers.try_recv() {
+                finished.push(result);
+            }
+        }
+
+        // Attach finished colliders, unless the chunk has been unloaded,
+        // re-meshed or left the radius in the meantime.
+        for result in finished {
+            self.pending.remove(&result.entity);
+            let current = entities.is_alive(result.entity)
+                && chunks.get(result.entity).map(|chunk| chunk.coord) == Some(result.coord);
+            if !current || result.indices.is_empty() {
+                continue;
+            }
+            let transform = transforms.get(result.entity).unwrap();
+            if closest_body(transform.translation()) > config.radius {
+                continue;
+            }
+            if config.debug_draw {
+                debug_meshes.meshes.insert(
+                    result.entity,
+                    wireframe(&result.points, &result.indices),
+                );
+            }
+
+            let shape = physics_world.shape_server().create(&ShapeDesc::TriMesh {
+                points: result.points,
+                indices: result.indices,
+            });
+            let rb = {
+                let mut rb_desc = RigidBodyDesc::default();
+                rb_desc.mode = BodyMode::Static;
+                physics_world.rigid_body_server().create(&rb_desc)
+            };
+            physics_world
+                .rigid_body_server()
+                .set_transform(rb.get(), transform.isometry());
+            shapes.insert(result.entity, shape).unwrap();
+            rigid_bodies.insert(result.entity, rb).unwrap();
+        }
+
+        let mut to_build = vec![];
+        let mut to_drop = vec![];
+        for (e, chunk, transform) in (&*entities, &chunks, &transforms).join() {
+            let dist = closest_body(transform.translation());
+            if dist <= config.radius && !shapes.contains(e) && !self.pending.contains(&e) {
+                let source = match (config.mesh, &chunk.surface) {
+                    (CollisionMesh::Downsampled(step), _) => ColliderSource::Density {
+                        terrain: Arc::clone(&*terrain),
+                        density: Arc::clone(&chunk.density),
+                        step: step.max(1),
+                    },
+                    (_, Some(surface)) => ColliderSource::Surface(Arc::clone(surface)),
+                    // Chunks only keep their surface when it's needed, so this doesn't happen.
+                    (_, None) => continue,
+                };
+                to_build.push((e, chunk.coord, source));
+            } else if dist > config.radius + chunk_size && shapes.contains(e) {
+                // The extra chunk length of slack stops colliders from being
+                // rebuilt over and over for a body sitting on the boundary.
+                to_drop.push(e);
+            }
+        }
+
+        // Dropping the handles frees the physics shape and rigid body.
+        for e in to_drop {
+            shapes.remove(e);
+            rigid_bodies.remove(e);
+            debug_meshes.meshes.remove(&e);
+        }
+
+        let tolerance = match config.mesh {
+            CollisionMesh::Simplified => config.tolerance,
+            _ => 0.0,
+        };
+        for (entity, coord, source) in to_build {
+            self.pending.insert(entity);
+            self.workers.submit(ColliderJob {
+                entity,
+                coord,
+                source,
+                tolerance,
+            });
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        let mut chunk_events = world.fetch_mut::<EventChannel<ChunkEvent>>();
+        self.chunk_event_reader = Some(chunk_events.register_reader());
+    }
+}
+
+/// Distance from `posn` to the closest point of the chunk whose minimum corner is `origin`.
+fn distance_to_chunk(posn: &Vector3<f32>, origin: &Vector3<f32>, chunk_size: f32) -> f32 {
+    let closest = Vector3::new(
+        posn.x.max(origin.x).min(origin.x + chunk_size),
+        posn.y.max(origin.y).min(origin.y + chunk_size),
+        posn.z.max(origin.z).min(origin.z + chunk_size),
+    );
+    return (posn - closest).magnitude();
+}
+
+/// Draws the collider wireframes kept in `CollisionDebugMeshes`.
+/// Runs every frame, unlike the physics systems, because debug lines only last one frame.
+pub struct CollisionDebugSystem;
+
+impl<'s> System<'s> for CollisionDebugSystem {
+    type SystemData = (
+        Read<'s, CollisionDebugMeshes>,
+        ReadStorage<'s, Transform>,
+        Write<'s, DebugLines>,
+    );
+
+    fn run(&mut self, (debug_meshes, transforms, mut debug_lines): Self::SystemData) {
+        let color = Srgba::new(0.1, 1.0, 0.2, 1.0);
+        for (e, lines) in &debug_meshes.meshes {
+            let origin = match transforms.get(*e) {
+                Some(transform) => *transform.translation(),
+                None => continue,
+            };
+            for (start, end) in lines {
+                debug_lines.draw_line(start + origin, end + origin, color);
+            }
+        }
+    }
+}
+
+fn build_collider(job: ColliderJob) -> ColliderResult {
+    let (points, indices) = match job.source {
+        ColliderSource::Surface(posns) => weld(posns.iter().cloned(), job.tolerance),
+        ColliderSource::Density {
+            terrain,
+            density,
+            step,
+        } => {
+            let (_, posns, _, _) = terrain
+                .mesh_downsampled(&density.to_matrix(), step)
+                .get_mesh_data();
+            weld(posns.iter().map(|p| p.0), job.tolerance)
+        }
+    };
+    return ColliderResult {
+        entity: job.entity,
+        coord: job.coord,
+        points,
+        indices,
+    };
+}
+
+/// Turns a triangle soup into an indexed mesh, merging vertices that share a
+/// position and dropping the triangles that become degenerate.
+/// With a positive `tolerance`, every vertex within the same cell of a grid with
+/// that spacing is merged into the first one seen, which simplifies the mesh.
+fn weld<I: Iterator<Item = [f32; 3]>>(
+    posns: I,
+    tolerance: f32,
+) -> (Vec<Point3<f32>>, Vec<Point3<usize>>) {
+    let mut lookup: HashMap<[i64; 3], usize> = HashMap::new();
+    let mut points = vec![];
+    let mut indices = vec![];
+    let mut tri = [0; 3];
+    for (i, p) in posns.enumerate() {
+        let key = if tolerance > 0.0 {
+            [
+                (p[0] / tolerance).round() as i64,
+                (p[1] / tolerance).round() as i64,
+                (p[2] / tolerance).round() as i64,
+            ]
+        } else {
+            // Adding 0.0 turns -0.0 into 0.0, so both hash the same.
+            [
+                (p[0] + 0.0).to_bits() as i64,
+                (p[1] + 0.0).to_bits() as i64,
+                (p[2] + 0.0).to_bits() as i64,
+            ]
+        };
+        tri[i % 3] = *lookup.entry(key).or_insert_with(|| {
+            points.push(Point3::new(p[0], p[1], p[2]));
+            points.len() - 1
+        });
+        if i % 3 == 2 && tri[0] != tri[1] && tri[1] != tri[2] && tri[0] != tri[2] {
+            indices.push(Point3::new(tri[0], tri[1], tri[2]));
+        }
+    }
+    return (points, indices);
+}
+
+/// The unique edges of a mesh.
+fn wireframe(points: &[Point3<f32>], indices: &[Point3<usize>]) -> Vec<(Point3<f32>, Point3<f32>)> {
+    let mut edges = HashSet::new();
+    for tri in indices {
+        for (a, b) in &[(tri.x, tri.y), (tri.y, tri.z), (tri.z, tri.x)] {
+            edges.insert((*a.min(b), *a.max(b)));
+        }
+    }
+    return edges
+        .into_iter()
+        .map(|(a, b)| (points[a], points[b]))
+        .collect();
+}
@@ -0,0 +1,173 @@
+use crate::{
+    chunk_registry::ChunkCoord,
+    components::Chunk,
+    config::{self, FileWatcher},
+    visual_utils, Terrain,
+};
+use amethyst::{
+    assets::{AssetStorage, Handle, Loader},
+    ecs::prelude::*,
+    renderer::{mtl, palette::LinSrgba, types::Texture, Material},
+    Error,
+};
+use log::{info, warn};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[derive(Deserialize, Clone)]
+pub struct MaterialConfig {
+    pub name: String,
+    pub color: (f32, f32, f32, f32),
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Only chunks centered below this height use the material.
+    #[serde(default)]
+    pub max_height: Option<f32>,
+}
+
+impl Default for MaterialConfig {
+    fn default() -> Self {
+        MaterialConfig {
+            name: String::from("default"),
+            color: (0.7188, 0.1578, 0.0, 1.0),
+            metallic: 0.0,
+            roughness: 1.0,
+            max_height: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TerrainMaterialsConfig {
+    materials: Vec<MaterialConfig>,
+}
+
+/// One material per terrain material, created once and shared by every chunk.
+pub struct TerrainMaterials {
+    configs: Vec<MaterialConfig>,
+    handles: Vec<Handle<Material>>,
+    watcher: FileWatcher,
+}
+
+impl TerrainMaterials {
+    /// Loads the materials at `path`, or falls back to a single default material
+    /// if they can't be read. The file is still watched, so fixing it reloads them.
+    pub fn load(world: &World, path: PathBuf) -> Self {
+        match load_configs(&path) {
+            Ok(configs) => return TerrainMaterials::with_configs(world, configs, path),
+            Err(e) => {
+                warn!("using the default terrain material: {}", e);
+                return TerrainMaterials::with_configs(
+                    world,
+                    vec![MaterialConfig::default()],
+                    path,
+                );
+            }
+        }
+    }
+
+    fn with_configs(world: &World, configs: Vec<MaterialConfig>, path: PathBuf) -> Self {
+        let handles = create_handles(
+            &configs,
+            &*world.read_resource::<Loader>(),
+            &*world.read_resource::<AssetStorage<Texture>>(),
+            &*world.read_resource::<AssetStorage<Material>>(),
+            &*world.read_resource::<mtl::MaterialDefaults>(),
+        );
+        TerrainMaterials {
+            configs,
+            handles,
+            watcher: FileWatcher::new(path),
+        }
+    }
+
+    /// The material for the chunk at `coord`, chosen by the height of its center.
+    pub fn for_chunk(&self, coord: &ChunkCoord, chunk_size: f32) -> Handle<Material> {
+        let height = coord.world_center(chunk_size).y;
+        let index = self
+            .configs
+            .iter()
+            .position(|config| config.max_height.map_or(true, |max| height < max))
+            .unwrap_or(self.configs.len() - 1);
+        return self.handles[index].clone();
+    }
+}
+
+fn load_configs(path: &Path) -> Result<Vec<MaterialConfig>, Error> {
+    let config: TerrainMaterialsConfig = config::load_ron(path)?;
+    if config.materials.is_empty() {
+        return Err(Error::from_string(format!(
+            "{} must define at least one material",
+            path.display()
+        )));
+    }
+    return Ok(config.materials);
+}
+
+fn create_handles(
+    configs: &[MaterialConfig],
+    loader: &Loader,
+    tex: &AssetStorage<Texture>,
+    mat: &AssetStorage<mtl::Material>,
+    mat_defaults: &mtl::MaterialDefaults,
+) -> Vec<Handle<Material>> {
+    return configs
+        .iter()
+        .map(|config| {
+            visual_utils::create_material(
+                loader,
+                tex,
+                mat,
+                mat_defaults,
+                LinSrgba::new(config.color.0, config.color.1, config.color.2, config.color.3),
+                config.metallic,
+                config.roughness,
+            )
+        })
+        .collect();
+}
+
+/// Recreates the terrain materials when their config file changes,
+/// and moves every chunk over to the new handles.
+pub struct TerrainMaterialsReloadSystem;
+
+impl<'s> System<'s> for TerrainMaterialsReloadSystem {
+    #[allow(clippy::type_complexity)]
+    type SystemData = (
+        WriteExpect<'s, TerrainMaterials>,
+        Read<'s, Arc<Terrain>>,
+        ReadExpect<'s, Loader>,
+        ReadExpect<'s, AssetStorage<Texture>>,
+        ReadExpect<'s, AssetStorage<mtl::Material>>,
+        ReadExpect<'s, mtl::MaterialDefaults>,
+        ReadStorage<'s, Chunk>,
+        WriteStorage<'s, Handle<Material>>,
+    );
+
+    fn run(
+        &mut self,
+        (mut materials, terrain, loader, tex, mat, mat_defaults, chunks, mut handles): Self::SystemData,
+    ) {
+        if !materials.watcher.changed() {
+            return;
+        }
+        let configs = match load_configs(materials.watcher.path()) {
+            Ok(configs) => configs,
+            Err(e) => {
+                warn!("not reloading terrain materials: {}", e);
+                return;
+            }
+        };
+        info!("reloaded {:?}", materials.watcher.path());
+        materials.handles = create_handles(&configs, &*loader, &*tex, &*mat, &*mat_defaults);
+        materials.configs = configs;
+
+        let chunk_size = terrain.chunk_size();
+        for (chunk, handle) in (&chunks, &mut handles).join() {
+            *handle = materials.for_chunk(&chunk.coord, chunk_size);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+/// A fixed set of threads that run `work` on submitted jobs.
+/// Results are collected without blocking through `try_recv`.
+pub struct WorkerPool<J, R> {
+    jobs: mpsc::Sender<J>,
+    results: mpsc::Receiver<R>,
+    in_flight: usize,
+}
+
+impl<J: Send + 'static, R: Send + 'static> WorkerPool<J, R> {
+    pub fn new<F>(threads: usize, work: F) -> Self
+    where
+        F: Fn(J) -> R + Send + Sync + 'static,
+    {
+        let (job_sender, job_receiver) = mpsc::channel::<J>();
+        let (result_sender, result_receiver) = mpsc::channel::<R>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let work = Arc::new(work);
+        for _ in 0..threads.max(1) {
+            let job_receiver = Arc::clone(&job_receiver);
+            let result_sender = result_sender.clone();
+            let work = Arc::clone(&work);
+            thread::spawn(move || loop {
+                // The lock is released at the end of this statement, so other
+                // workers can pick up jobs while this one is busy.
+                let job = match job_receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                if result_sender.send(work(job)).is_err() {
+                    return;
+                }
+            });
+        }
+        WorkerPool {
+            jobs: job_sender,
+            results: result_receiver,
+            in_flight: 0,
+        }
+    }
+
+    pub fn submit(&mut self, job: J) {
+        self.jobs.send(job).unwrap();
+        self.in_flight += 1;
+    }
+
+    pub fn try_recv(&mut self) -> Option<R> {
+        match self.results.try_recv() {
+            Ok(result) => {
+                self.in_flight -= 1;
+                Some(result)
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Waits for the next result, or returns `None` if no jobs are in flight.
+    pub fn recv(&mut self) -> Option<R> {
+        if self.in_flight == 0 {
+            return None;
+        }
+        match self.results.recv() {
+            Ok(result) => {
+                self.in_flight -= 1;
+                Some(result)
+            }
+            Err(_) => None,
+        }
+    }
+}